@@ -0,0 +1,384 @@
+use axum::{
+    routing::post,
+    Router,
+    Json,
+    Extension,
+    response::Response,
+    response::IntoResponse,
+    http::StatusCode,
+};
+use llamacpp_bindings::{TextGenerator, ModelType, LoadParams, KvOverride, InferenceParams, CancellationToken, ChatMessage, Completion, ContextOverflow, LlmError, TokenLogprob, json_schema_to_grammar};
+use std::path::PathBuf;
+use std::sync::Arc;
+use axum::extract::State;
+use crate::{ CompletionRequest, EmbeddingRequest, LoadModelRequest, TokenizeRequest };
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use std::num::NonZero;
+use serde_json::json;
+
+/// Loaded models by name
+pub type Models<B> = Arc<RwLock<HashMap<String, Arc<B>>>>;
+
+/// Routes that work with any `TextGenerator`.
+///
+/// `/completions`, `/embeddings`, `/tokenize` and `/load_model` are served
+/// here, so the server can run on `MockBackend` in tests:
+/// `router::<MockBackend>(LoadParams::default()).with_state(models)`.
+pub fn router<B: TextGenerator + 'static>(load_params: LoadParams) -> Router<Models<B>> {
+    Router::new()
+        .route("/completions", post(serve_completions::<B>))
+        .route("/embeddings", post(serve_embeddings::<B>))
+        .route("/tokenize", post(serve_tokenize::<B>))
+        .route("/load_model", post(load_model_handler::<B>))
+        .layer(Extension(load_params))
+}
+
+async fn load_model_handler<B: TextGenerator + 'static>(
+    State(models): State<Models<B>>,
+    Extension(load_params): Extension<LoadParams>,
+    Json(payload): Json<LoadModelRequest>,
+) -> Response {
+    // Determine the source of the model
+    let model_type = if !payload.model_path.is_empty() {
+        ModelType::Local { path: PathBuf::from(payload.model_path) }
+    } else if let (Some(repo), Some(file)) = (payload.hf_repo, payload.hf_file) {
+        ModelType::HuggingFace { repo, file, revision: payload.hf_revision }
+    } else {
+        return bad_request("Either a model path or a HuggingFace repo and file must be provided".to_string());
+    };
+
+
+    // Check if the model is already loaded
+    {
+        let models_lock = models.read().await;
+        if models_lock.contains_key(&payload.model_name) {
+            return IntoResponse::into_response(format!("Warning: Model {} already loaded", payload.model_name));
+        }
+    } // drop the lock
+
+    let mut kv_overrides = Vec::new();
+    for (key, value) in payload.kv_overrides {
+        let value = match value {
+            serde_json::Value::Bool(value) => KvOverride::Bool(value),
+            serde_json::Value::Number(number) if number.is_f64() => KvOverride::Float(number.as_f64().unwrap()),
+            serde_json::Value::Number(number) if number.is_i64() => KvOverride::Int(number.as_i64().unwrap()),
+            serde_json::Value::String(value) => KvOverride::Str(value),
+            other => return bad_request(format!("Unsupported value {} for override {}", other, key)),
+        };
+        kv_overrides.push((key, value));
+    }
+
+    // Load the model
+    println!("Loading model: {}", payload.model_name);
+    let load_params = LoadParams {
+        chat_template: payload.chat_template,
+        kv_overrides,
+        expected_sha256: payload.expected_sha256,
+        ..load_params
+    };
+
+    // Loading is aborted when the client disconnects
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    let model_name = payload.model_name.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut reported = 0;
+        B::load_with_progress(model_type, load_params, &mut |progress| {
+            let percent = (progress * 100.0) as u32;
+            if percent >= reported + 10 {
+                reported = percent - percent % 10;
+                println!("Loading model {}: {}%", model_name, reported);
+            }
+            !cancel.is_cancelled()
+        })
+    })
+    .await
+    .unwrap();
+    let model = match result {
+        Ok(model) => model,
+        Err(e) => return error_response(e),
+    };
+
+    let mut models_lock = models.write().await;
+    models_lock.insert(payload.model_name.clone(), Arc::new(model));
+    
+    Json(json!({
+        "Message": format!("{} Model loaded", payload.model_name)
+    })).into_response()
+
+}
+
+async fn serve_completions<B: TextGenerator + 'static>(State(models): State<Models<B>>, Json(payload): Json<CompletionRequest>) -> Response {
+    let model_name = payload.model.clone();
+    let mut inference_params = match completion_params(&payload) {
+        Ok(inference_params) => inference_params,
+        Err(response) => return response,
+    };
+
+    {
+        let models_lock = models.read().await;
+        if !models_lock.contains_key(&model_name) {
+            return model_not_loaded(&model_name);
+        }
+    }
+
+    let model = {
+        let models_lock = models.read().await;
+        models_lock.get(&model_name).cloned().unwrap()
+    };
+
+    let prompt = match completion_prompt(model.as_ref(), &payload) {
+        Ok(prompt) => prompt,
+        Err(response) => return response,
+    };
+
+    // Axum drops this future when the client disconnects, which cancels the generation
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    inference_params.cancel = Some(cancel);
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut i = 1;
+        println!("Prompt: {}", prompt);
+        // Start a timer
+        let start = std::time::Instant::now();
+        let completion = model.predict(&prompt, inference_params, &mut |token| {
+            println!("{}: Token: {}", i, token);
+            i += 1;
+        });
+
+        // End the timer
+        let end = std::time::Instant::now();
+        println!("Time taken: {:?}", end.duration_since(start));
+        completion
+
+    })
+    .await
+    .unwrap();
+
+    match result {
+        Ok(response) => Json(completion_json(&payload, &response)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Builds the inference parameters of a completion request
+pub fn completion_params(payload: &CompletionRequest) -> Result<InferenceParams, Response> {
+    let Some(n_ctx) = NonZero::new(payload.n_ctx) else {
+        return Err(bad_request("n_ctx must be greater than 0".to_string()));
+    };
+    let mut inference_params = InferenceParams {
+        max_tokens: payload.max_tokens,
+        seed: payload.seed,
+        n_threads: Some(payload.n_threads),
+        n_ctx,
+        stop: payload.stop.clone(),
+        logprobs: payload.logprobs,
+        echo: payload.echo,
+        lora_adapters: payload.lora_adapters.iter().map(|lora| (lora.name.clone(), lora.scale)).collect(),
+        ..Default::default()
+    };
+    // Sampling fields are optional, anything left out keeps the bindings' defaults
+    if let Some(temperature) = payload.temperature {
+        inference_params.temperature = temperature;
+    }
+    if let Some(top_k) = payload.top_k {
+        inference_params.top_k = top_k;
+    }
+    if let Some(top_p) = payload.top_p {
+        inference_params.top_p = top_p;
+    }
+    if let Some(min_p) = payload.min_p {
+        inference_params.min_p = min_p;
+    }
+    if let Some(repeat_penalty) = payload.repeat_penalty {
+        inference_params.repeat_penalty = repeat_penalty;
+    }
+    inference_params.context_overflow = match payload.context_overflow.as_deref() {
+        None | Some("error") => ContextOverflow::Error,
+        Some("stop") => ContextOverflow::Stop,
+        // The BOS token is always kept, like llama.cpp's server does
+        Some("shift") => ContextOverflow::Shift { n_keep: payload.n_keep + 1 },
+        Some(other) => return Err(bad_request(format!("Unknown context_overflow {}, expected error, stop or shift", other))),
+    };
+    inference_params.grammar = match (&payload.grammar, &payload.json_schema) {
+        (Some(grammar), _) => Some(grammar.clone()),
+        (None, Some(schema)) => match json_schema_to_grammar(schema) {
+            Ok(grammar) => Some(grammar),
+            Err(e) => return Err(error_response(e)),
+        },
+        (None, None) => None,
+    };
+    Ok(inference_params)
+}
+
+/// Builds the prompt of a completion request
+pub fn completion_prompt<B: TextGenerator>(model: &B, payload: &CompletionRequest) -> Result<String, Response> {
+    // Instruct models expect their chat template, raw requests skip it
+    if payload.raw {
+        return Ok(payload.prompt.clone());
+    }
+    let messages: Vec<ChatMessage> = match &payload.messages {
+        Some(messages) => messages
+            .iter()
+            .map(|message| ChatMessage::new(message.role.clone(), message.content.clone()))
+            .collect(),
+        None => vec![ChatMessage::new("user", payload.prompt.clone())],
+    };
+    match model.apply_chat_template(&messages, true) {
+        Ok(prompt) => Ok(prompt),
+        // Plain prompts still work for base models without a template, and
+        // for templates llama.cpp does not recognise
+        Err(e) if payload.messages.is_none() => {
+            if model.chat_template().is_some() {
+                println!("Using the raw prompt, the chat template could not be applied: {}", error_message(&e));
+            }
+            Ok(payload.prompt.clone())
+        }
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+/// Response body of a completion
+pub fn completion_json(payload: &CompletionRequest, completion: &Completion) -> serde_json::Value {
+    let stats = &completion.stats;
+    let mut body = json!({
+        "Message": completion.text,
+        "finish_reason": completion.finish_reason.as_str(),
+        "usage": {
+            "prompt_tokens": stats.n_prompt_tokens,
+            "completion_tokens": stats.n_generated_tokens,
+            "total_tokens": stats.n_prompt_tokens + stats.n_generated_tokens,
+        },
+        "timings": {
+            "prompt_ms": stats.prompt_eval_time.as_secs_f64() * 1000.0,
+            "generation_ms": stats.generation_time.as_secs_f64() * 1000.0,
+            "time_to_first_token_ms": stats.time_to_first_token.as_secs_f64() * 1000.0,
+            "tokens_per_second": stats.tokens_per_second,
+        }
+    });
+    if payload.logprobs.is_some() {
+        body["logprobs"] = logprobs_json(&completion.logprobs);
+    }
+    if payload.echo {
+        body["prompt_logprobs"] = logprobs_json(&completion.prompt_logprobs);
+    }
+    body
+}
+
+/// Maps an error from the bindings to an HTTP status code and an error message
+pub fn error_response(error: LlmError) -> Response {
+    let status = match &error {
+        LlmError::FileNotFound { .. } => StatusCode::NOT_FOUND,
+        LlmError::InvalidModel { .. } | LlmError::DigestMismatch { .. } | LlmError::InvalidState(_) | LlmError::Tokenize(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        LlmError::Download { .. } => StatusCode::BAD_GATEWAY,
+        LlmError::ContextOverflow { .. } | LlmError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        // Memory is freed once other requests finish, the client may retry later
+        LlmError::OutOfMemory(_) | LlmError::Aborted | LlmError::Cancelled => StatusCode::SERVICE_UNAVAILABLE,
+        LlmError::Read { .. } | LlmError::Write { .. } | LlmError::Llama { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, format!("Error: {}", error_message(&error))).into_response()
+}
+
+/// `400 Bad Request` for a request the handlers reject before reaching the model
+pub fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, format!("Error: {}", message)).into_response()
+}
+
+/// `404 Not Found` for a request naming a model that is not loaded
+pub fn model_not_loaded(model_name: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        format!("Error: Model {} not loaded. Please load the model using the /load_model endpoint.", model_name),
+    )
+        .into_response()
+}
+
+/// The error followed by each of its sources
+pub fn error_message(error: &LlmError) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Cancels the token when dropped
+pub struct CancelOnDrop(pub CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Log-probabilities in the response format
+pub fn logprobs_json(entries: &[TokenLogprob]) -> serde_json::Value {
+    entries
+        .iter()
+        .map(|entry| json!({
+            "token": entry.token,
+            "text": entry.text,
+            "logprob": entry.logprob,
+            "top_logprobs": entry.top_logprobs.iter().map(|top| json!({
+                "token": top.token,
+                "text": top.text,
+                "logprob": top.logprob,
+            })).collect::<Vec<_>>(),
+        }))
+        .collect()
+}
+
+async fn serve_embeddings<B: TextGenerator + 'static>(State(models): State<Models<B>>, Json(payload): Json<EmbeddingRequest>) -> Response {
+    let model = {
+        let models_lock = models.read().await;
+        match models_lock.get(&payload.model) {
+            Some(model) => model.clone(),
+            None => return model_not_loaded(&payload.model),
+        }
+    };
+
+    let mut inference_params = InferenceParams::default();
+    if let Some(normalize) = payload.normalize {
+        inference_params.normalize_embeddings = normalize;
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let inputs: Vec<&str> = payload.input.iter().map(String::as_str).collect();
+        model.embed(&inputs, inference_params)
+    })
+    .await
+    .unwrap();
+
+    match result {
+        Ok(embeddings) => Json(json!({
+            "Message": embeddings
+        })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn serve_tokenize<B: TextGenerator + 'static>(State(models): State<Models<B>>, Json(payload): Json<TokenizeRequest>) -> Response {
+    let model = {
+        let models_lock = models.read().await;
+        match models_lock.get(&payload.model) {
+            Some(model) => model.clone(),
+            None => return model_not_loaded(&payload.model),
+        }
+    };
+
+    let add_bos = payload.add_bos.unwrap_or(true);
+    let parse_special = payload.parse_special.unwrap_or(false);
+    match model.tokenize(&payload.text, add_bos, parse_special) {
+        Ok(tokens) => Json(json!({
+            "Message": {
+                "count": tokens.len(),
+                "tokens": tokens,
+            }
+        })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
@@ -1,13 +1,58 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub mod handlers;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompletionRequest {
     pub model: String,
+    #[serde(default)]
     pub prompt: String,
     pub max_tokens: i32,
     pub seed: u32,
     pub n_threads: i32,
     pub n_ctx: u32,
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// GBNF grammar the completion must follow
+    pub grammar: Option<String>,
+    /// JSON Schema the completion must follow, ignored when `grammar` is set
+    pub json_schema: Option<serde_json::Value>,
+    /// Number of alternatives returned with each token's log-probability
+    pub logprobs: Option<usize>,
+    /// Also return the log-probabilities of the prompt tokens
+    #[serde(default)]
+    pub echo: bool,
+    /// Conversation to format with the model's chat template, replaces `prompt`
+    pub messages: Option<Vec<Message>>,
+    /// Send `prompt` to the model as is, without applying the chat template
+    #[serde(default)]
+    pub raw: bool,
+    /// LoRA adapters loaded with `/load_lora` to apply to this request
+    #[serde(default)]
+    pub lora_adapters: Vec<LoraSelection>,
+    /// `error`, `stop` or `shift`, what happens when the context is full
+    pub context_overflow: Option<String>,
+    /// Number of leading prompt tokens kept when the context is shifted, the BOS token is kept on top of these
+    #[serde(default)]
+    pub n_keep: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoraSelection {
+    pub name: String,
+    pub scale: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
 }
 
 impl Default for CompletionRequest {
@@ -19,6 +64,21 @@ impl Default for CompletionRequest {
             seed: 1234,
             n_threads: 5,
             n_ctx: 2048,
+            temperature: None,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            repeat_penalty: None,
+            stop: Vec::new(),
+            grammar: None,
+            json_schema: None,
+            logprobs: None,
+            echo: false,
+            messages: None,
+            raw: false,
+            lora_adapters: Vec::new(),
+            context_overflow: None,
+            n_keep: 0,
         }
     }
 }
@@ -26,5 +86,40 @@ impl Default for CompletionRequest {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LoadModelRequest {
     pub model_name: String,
+    #[serde(default)]
     pub model_path: String,
-}
\ No newline at end of file
+    /// HuggingFace repository id, used when `model_path` is empty
+    pub hf_repo: Option<String>,
+    /// GGUF file inside `hf_repo`
+    pub hf_file: Option<String>,
+    pub hf_revision: Option<String>,
+    /// Overrides the chat template stored in the GGUF file
+    pub chat_template: Option<String>,
+    /// GGUF metadata overrides, JSON integers, floats, booleans and strings keep their type
+    #[serde(default)]
+    pub kv_overrides: HashMap<String, serde_json::Value>,
+    /// Hex SHA-256 digest the model file must have
+    pub expected_sha256: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: Vec<String>,
+    pub normalize: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TokenizeRequest {
+    pub model: String,
+    pub text: String,
+    pub add_bos: Option<bool>,
+    pub parse_special: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LoadLoraRequest {
+    pub model_name: String,
+    pub adapter_name: String,
+    pub adapter_path: String,
+}
@@ -4,16 +4,18 @@ use axum::{
     Json,
     response::Response,
     response::IntoResponse,
-
+    response::sse::{Event, Sse},
 };
-use llamacpp_bindings::{LLM, ModelType, LoadParams, InferenceParams};
+use futures::StreamExt;
+use llamacpp_bindings::{LLM, LoadParams};
+use std::convert::Infallible;
 use std::path::PathBuf;
 use std::sync::Arc;
 use axum::extract::State;
-use inference_server::{ CompletionRequest, LoadModelRequest };
+use inference_server::{ CompletionRequest, LoadLoraRequest };
+use inference_server::handlers::{router, completion_json, completion_params, completion_prompt, error_message, error_response, model_not_loaded, Models};
 use std::collections::HashMap;
 use tokio::sync::RwLock;
-use std::num::NonZero;
 use serde_json::json;
 use clap::Command;
 
@@ -30,18 +32,38 @@ async fn main() {
                 .help("Sets the port to use")
                 .required(false)
         )
+        .arg(
+            clap::Arg::new("hf-cache-dir")
+                .long("hf-cache-dir")
+                .value_name("DIR")
+                .help("HuggingFace cache used to resolve models loaded by repo id")
+                .required(false)
+        )
+        .arg(
+            clap::Arg::new("hf-offline")
+                .long("hf-offline")
+                .help("Only load HuggingFace models that are already in the cache")
+                .action(clap::ArgAction::SetTrue)
+        )
         .get_matches();
 
     let default_port = "3000".to_string();
     let port = matches.get_one::<String>("port").unwrap_or(&default_port);
 
-    let models: Arc<RwLock<HashMap<String, Arc<LLM>>>> = Arc::new(RwLock::new(HashMap::new()));
+    let load_params = LoadParams {
+        hf_cache_dir: matches.get_one::<String>("hf-cache-dir").map(PathBuf::from),
+        hf_offline: matches.get_flag("hf-offline"),
+        ..Default::default()
+    };
+
+    let models: Models<LLM> = Arc::new(RwLock::new(HashMap::new()));
     
     let app = Router::new()
         .route("/", post(handle_post))
-        .route("/completions", post(serve_completions))
-        .route("/load_model", post(load_model_handler))
+        .route("/completions/stream", post(serve_completions_stream))
+        .route("/load_lora", post(load_lora_handler))
         .route("/status", get(status_handler))
+        .merge(router::<LLM>(load_params))
         .with_state(models.clone());
 
     let address = format!("127.0.0.1:{}", port);
@@ -52,103 +74,112 @@ async fn main() {
 
 // async fn is_model_loaded(model_name: String, model_s) -> bool {}
 
-async fn load_model_handler(
-    State(models): State<Arc<RwLock<HashMap<String, Arc<LLM>>>>>,
-    Json(payload): Json<LoadModelRequest>,
+async fn load_lora_handler(
+    State(models): State<Models<LLM>>,
+    Json(payload): Json<LoadLoraRequest>,
 ) -> Response {
-    // Determine the source of the model
-    let model_path = if payload.model_path.is_empty() {
-        return IntoResponse::into_response("Error: Model path must be provided".to_string());
-    } else {
-        PathBuf::from(payload.model_path)
-    };
-
-
-    // Check if the model is already loaded
-    {
+    let model = {
         let models_lock = models.read().await;
-        if models_lock.contains_key(&payload.model_name) {
-            return IntoResponse::into_response(format!("Warning: Model {} already loaded", payload.model_name));
+        match models_lock.get(&payload.model_name) {
+            Some(model) => model.clone(),
+            None => return model_not_loaded(&payload.model_name),
         }
-    } // drop the lock
-
-    // Load the model
-    println!("Loading model: {}", payload.model_name);
-    let model = LLM::load(ModelType::Local { path: model_path }, LoadParams::default()).unwrap();
+    };
 
-    let mut models_lock = models.write().await;
-    models_lock.insert(payload.model_name.clone(), Arc::new(model));
-    
-    Json(json!({
-        "Message": format!("{} Model loaded", payload.model_name)
-    })).into_response()
+    println!("Loading LoRA adapter {} for model {}", payload.adapter_name, payload.model_name);
+    let result = tokio::task::spawn_blocking(move || {
+        model.load_lora_adapter(&payload.adapter_name, &payload.adapter_path)
+            .map(|_| payload.adapter_name)
+    })
+    .await
+    .unwrap();
 
+    match result {
+        Ok(adapter_name) => Json(json!({
+            "Message": format!("{} LoRA adapter loaded", adapter_name)
+        })).into_response(),
+        Err(e) => error_response(e),
+    }
 }
 
 async fn status_handler(
-    State(models): State<Arc<RwLock<HashMap<String, Arc<LLM>>>>>
+    State(models): State<Models<LLM>>
 ) -> Response {
     let models_lock = models.read().await;
     let model_names: Vec<String> = models_lock.keys().cloned().collect();
     
     // Join the model names into a single string separated by newlines
     let response_text = model_names.join("\n");
+
+    let details: Vec<serde_json::Value> = models_lock
+        .iter()
+        .map(|(name, model)| {
+            let info = model.info();
+            json!({
+                "name": name,
+                "architecture": info.architecture,
+                "n_params": info.n_params,
+                "n_ctx_train": info.n_ctx_train,
+                "n_embd": info.n_embd,
+                "n_layer": info.n_layer,
+                "n_vocab": info.n_vocab,
+                "quantization": info.quantization,
+                "file_size": info.file_size,
+                "sha256": info.sha256,
+                "metadata": info.metadata,
+                "lora_adapters": model.lora_adapter_names(),
+            })
+        })
+        .collect();
     
         Json(json!({
-            "Message": response_text
+            "Message": response_text,
+            "models": details
         })).into_response()
 }
 
 
-async fn serve_completions(State(models): State<Arc<RwLock<HashMap<String, Arc<LLM>>>>>, Json(payload): Json<CompletionRequest>) -> Response {
-    let user_prompt = payload.prompt.clone();
-    let prompt = format!("{}", user_prompt);
-    let model_name = payload.model.clone();
-    let max_tokens = payload.max_tokens;
-    let inference_params = InferenceParams {
-        max_tokens: max_tokens,
-        seed: payload.seed,
-        n_threads: Some(payload.n_threads),
-        n_ctx: NonZero::new(payload.n_ctx).unwrap(),
-        ..Default::default()
+/// Streams the completion as server-sent events.
+///
+/// Each event's data is `{"text", "token"}`, the last one also carries the
+/// fields returned by `/completions`. Closing the connection drops the
+/// stream, which stops the generation.
+async fn serve_completions_stream(State(models): State<Models<LLM>>, Json(payload): Json<CompletionRequest>) -> Response {
+    let inference_params = match completion_params(&payload) {
+        Ok(inference_params) => inference_params,
+        Err(response) => return response,
     };
 
-
-    {
-        let models_lock = models.read().await;
-        if !models_lock.contains_key(&model_name) {
-            return IntoResponse::into_response(format!("Error: Model {} not loaded. Please load the model using the /load_model endpoint.", model_name));
-        }
-    }
-
     let model = {
         let models_lock = models.read().await;
-        models_lock.get(&model_name).cloned().unwrap()
+        match models_lock.get(&payload.model) {
+            Some(model) => model.clone(),
+            None => return model_not_loaded(&payload.model),
+        }
     };
 
-    let response = tokio::task::spawn_blocking(move || {
-        let mut i = 1;
-        println!("Prompt: {}", prompt);
-        // Start a timer
-        let start = std::time::Instant::now();
-        let generated_text = model.predict(&prompt, inference_params, |token| {
-            println!("{}: Token: {}", i, token);
-            i += 1;
-        }).unwrap();
-
-        // End the timer
-        let end = std::time::Instant::now();
-        println!("Time taken: {:?}", end.duration_since(start));
-        generated_text
-
-    })
-    .await
-    .unwrap();
+    let prompt = match completion_prompt(model.as_ref(), &payload) {
+        Ok(prompt) => prompt,
+        Err(response) => return response,
+    };
 
-    // Convert the response struct to JSON
-    Json(json!({
-        "Message": response
-    })).into_response()
+    let events = model.stream_async(prompt, inference_params).map(move |event| {
+        let data = match event {
+            Ok(event) => {
+                let mut data = json!({
+                    "text": event.text,
+                    "token": event.token,
+                });
+                if let Some(completion) = &event.completion {
+                    data["completion"] = completion_json(&payload, completion);
+                }
+                data
+            }
+            Err(e) => json!({ "error": error_message(&e) }),
+        };
+        Ok::<_, Infallible>(Event::default().data(data.to_string()))
+    });
+    Sse::new(events).into_response()
 }
 
 async fn handle_post(Json(payload): Json<serde_json::Value>) -> Response {
@@ -47,6 +47,12 @@ async fn request_load_model(client: &Client) -> Result<(), Box<dyn Error>> {
     let request_body = LoadModelRequest {
         model_name: "Dobby Unhinged".to_string(),
         model_path: "/home/ec2-user/pipeline/pipeline-tee.rs/reference_apps/inference_server/dobby-8b-unhinged-q4_k_m.gguf".to_string(),
+        hf_repo: None,
+        hf_file: None,
+        hf_revision: None,
+        chat_template: None,
+        kv_overrides: Default::default(),
+        expected_sha256: None,
     };
 
     let response = client
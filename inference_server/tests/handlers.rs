@@ -0,0 +1,252 @@
+//! Drives the generic handlers with `MockBackend`, no model file needed
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use inference_server::handlers::{self, Models};
+use inference_server::{CompletionRequest, EmbeddingRequest, LoadModelRequest, TokenizeRequest};
+use llamacpp_bindings::{LlmError, LoadParams, MockBackend};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+/// Server state with `backend` loaded as `name`
+async fn models_with(name: &str, backend: MockBackend) -> Models<MockBackend> {
+    let models = Models::default();
+    models.write().await.insert(name.to_string(), Arc::new(backend));
+    models
+}
+
+/// Posts `body` as JSON and returns the status with the response body
+async fn post<T: Serialize>(models: &Models<MockBackend>, uri: &str, body: &T) -> (StatusCode, String) {
+    let app = handlers::router::<MockBackend>(LoadParams::default()).with_state(models.clone());
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Posts `body` and parses the JSON of a successful response
+async fn post_ok<T: Serialize>(models: &Models<MockBackend>, uri: &str, body: &T) -> Value {
+    let (status, body) = post(models, uri, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    serde_json::from_str(&body).unwrap_or_else(|_| panic!("not JSON: {}", body))
+}
+
+fn completion(model: &str, prompt: &str) -> CompletionRequest {
+    CompletionRequest {
+        model: model.to_string(),
+        prompt: prompt.to_string(),
+        raw: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn load_model_then_tokenize() {
+    let models = Models::default();
+    let load = LoadModelRequest {
+        model_name: "mock".to_string(),
+        model_path: "mock.gguf".to_string(),
+        hf_repo: None,
+        hf_file: None,
+        hf_revision: None,
+        chat_template: None,
+        kv_overrides: Default::default(),
+        expected_sha256: None,
+    };
+    let body = post_ok(&models, "/load_model", &load).await;
+    assert_eq!(body["Message"], "mock Model loaded");
+    assert!(models.read().await.contains_key("mock"));
+
+    let tokenize = TokenizeRequest {
+        model: "mock".to_string(),
+        text: "Hello world".to_string(),
+        add_bos: None,
+        parse_special: None,
+    };
+    let body = post_ok(&models, "/tokenize", &tokenize).await;
+    assert_eq!(body["Message"]["count"], 3);
+    let without_bos = TokenizeRequest {
+        add_bos: Some(false),
+        ..tokenize
+    };
+    let tokens = &post_ok(&models, "/tokenize", &without_bos).await["Message"]["tokens"];
+    assert_eq!(tokens.as_array().unwrap()[..], body["Message"]["tokens"].as_array().unwrap()[1..]);
+}
+
+#[tokio::test]
+async fn completion_echoes_a_raw_prompt() {
+    let models = models_with("echo", MockBackend::echo()).await;
+    let body = post_ok(&models, "/completions", &completion("echo", "one two three")).await;
+    assert_eq!(body["Message"], "one two three");
+    assert_eq!(body["finish_reason"], "stop");
+    assert_eq!(body["usage"]["prompt_tokens"], 4);
+    assert_eq!(body["usage"]["completion_tokens"], 3);
+    assert_eq!(body["usage"]["total_tokens"], 7);
+}
+
+#[tokio::test]
+async fn completion_applies_the_chat_template() {
+    let models = models_with("echo", MockBackend::echo()).await;
+    let request = CompletionRequest {
+        raw: false,
+        ..completion("echo", "Hi")
+    };
+    let body = post_ok(&models, "/completions", &request).await;
+    assert_eq!(body["Message"], "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n");
+}
+
+#[tokio::test]
+async fn completion_ends_at_a_stop_string() {
+    let models = models_with("script", MockBackend::scripted(["The", " answer", " is", " 42", "."])).await;
+    let request = CompletionRequest {
+        stop: vec![" is".to_string()],
+        ..completion("script", "question")
+    };
+    let body = post_ok(&models, "/completions", &request).await;
+    assert_eq!(body["Message"], "The answer");
+    assert_eq!(body["finish_reason"], "stop");
+}
+
+#[tokio::test]
+async fn completion_honours_max_tokens() {
+    let models = models_with("script", MockBackend::scripted(["The", " answer", " is", " 42", "."])).await;
+    let request = CompletionRequest {
+        max_tokens: 2,
+        ..completion("script", "question")
+    };
+    let body = post_ok(&models, "/completions", &request).await;
+    assert_eq!(body["Message"], "The answer");
+    assert_eq!(body["finish_reason"], "length");
+    assert_eq!(body["usage"]["completion_tokens"], 2);
+}
+
+#[tokio::test]
+async fn completion_returns_logprobs() {
+    let models = models_with("script", MockBackend::scripted(["Yes", "."])).await;
+    let request = CompletionRequest {
+        logprobs: Some(0),
+        ..completion("script", "question")
+    };
+    let body = post_ok(&models, "/completions", &request).await;
+    let logprobs = body["logprobs"].as_array().unwrap();
+    assert_eq!(logprobs.len(), 2);
+    assert_eq!(logprobs[0]["text"], "Yes");
+    assert_eq!(logprobs[0]["logprob"], 0.0);
+}
+
+#[tokio::test]
+async fn completion_errors_map_to_status_codes() {
+    let models = models_with("echo", MockBackend::echo()).await;
+    let (status, body) = post(&models, "/completions", &completion("echo", "")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, "Error: prompt is empty");
+
+    let request = CompletionRequest {
+        n_ctx: 2,
+        ..completion("echo", "one two three")
+    };
+    let (status, body) = post(&models, "/completions", &request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.starts_with("Error: context overflow"), "{}", body);
+
+    let request = CompletionRequest {
+        context_overflow: Some("wrap".to_string()),
+        ..completion("echo", "one")
+    };
+    let (status, body) = post(&models, "/completions", &request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.starts_with("Error: Unknown context_overflow wrap"), "{}", body);
+
+    let request = CompletionRequest {
+        n_ctx: 0,
+        ..completion("echo", "one")
+    };
+    let (status, body) = post(&models, "/completions", &request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body, "Error: n_ctx must be greater than 0");
+}
+
+#[tokio::test]
+async fn unknown_models_are_reported() {
+    let models = Models::default();
+    let (status, body) = post(&models, "/completions", &completion("missing", "one")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body.starts_with("Error: Model missing not loaded"), "{}", body);
+
+    let tokenize = TokenizeRequest {
+        model: "missing".to_string(),
+        text: "one".to_string(),
+        add_bos: None,
+        parse_special: None,
+    };
+    let (status, _) = post(&models, "/tokenize", &tokenize).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn invalid_load_requests_are_rejected() {
+    let models = Models::default();
+    let mut load = LoadModelRequest {
+        model_name: "mock".to_string(),
+        model_path: String::new(),
+        hf_repo: None,
+        hf_file: None,
+        hf_revision: None,
+        chat_template: None,
+        kv_overrides: Default::default(),
+        expected_sha256: None,
+    };
+    let (status, body) = post(&models, "/load_model", &load).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.starts_with("Error: Either a model path"), "{}", body);
+
+    load.model_path = "mock.gguf".to_string();
+    load.kv_overrides.insert("general.name".to_string(), serde_json::json!(["not", "a", "scalar"]));
+    let (status, body) = post(&models, "/load_model", &load).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.starts_with("Error: Unsupported value"), "{}", body);
+    assert!(models.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn embeddings_are_returned_per_input() {
+    let models = models_with("echo", MockBackend::echo()).await;
+    let request = EmbeddingRequest {
+        model: "echo".to_string(),
+        input: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+        normalize: Some(true),
+    };
+    let body = post_ok(&models, "/embeddings", &request).await;
+    let embeddings = body["Message"].as_array().unwrap();
+    assert_eq!(embeddings.len(), 3);
+    assert_eq!(embeddings[0], embeddings[2]);
+    assert_ne!(embeddings[0], embeddings[1]);
+    let norm: f64 = embeddings[0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x.as_f64().unwrap().powi(2))
+        .sum::<f64>()
+        .sqrt();
+    assert!((norm - 1.0).abs() < 1e-4);
+
+    let request = EmbeddingRequest {
+        input: vec![String::new()],
+        ..request
+    };
+    let (status, _) = post(&models, "/embeddings", &request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn aborted_loads_can_be_retried() {
+    // `/load_model` aborts when the client disconnects, which `MockBackend` cannot be slow enough to show
+    let response = handlers::error_response(LlmError::Aborted);
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,74 @@
+use crate::{ChatMessage, Completion, InferenceParams, LlmError, LoadParams, ModelType, Result, LLM};
+
+/// Text generation backend.
+///
+/// `LLM` is the llama.cpp implementation and `MockBackend` a deterministic
+/// one that needs no model file. Code written against this trait, such as
+/// server handlers, can be exercised with the mock in tests.
+pub trait TextGenerator: Send + Sync {
+    /// Loads a model, reporting progress between `0.0` and `1.0`.
+    ///
+    /// Returning `false` from `progress` aborts the load.
+    fn load_with_progress(
+        model_type: ModelType,
+        load_params: LoadParams,
+        progress: &mut dyn FnMut(f32) -> bool,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn load(model_type: ModelType, load_params: LoadParams) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::load_with_progress(model_type, load_params, &mut |_| true)
+    }
+
+    /// Generates a completion for `prompt`, passing each piece of text to `callback`
+    fn predict(&self, prompt: &str, inference_params: InferenceParams, callback: &mut dyn FnMut(&str)) -> Result<Completion>;
+
+    fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<u32>>;
+
+    /// Computes one embedding per input
+    fn embed(&self, inputs: &[&str], inference_params: InferenceParams) -> Result<Vec<Vec<f32>>>;
+
+    /// Chat template used by `apply_chat_template`, if any
+    fn chat_template(&self) -> Option<String> {
+        None
+    }
+
+    /// Formats `messages` into a prompt using the backend's chat template
+    fn apply_chat_template(&self, _messages: &[ChatMessage], _add_generation_prompt: bool) -> Result<String> {
+        Err(LlmError::invalid_request("the backend has no chat template"))
+    }
+}
+
+impl TextGenerator for LLM {
+    fn load_with_progress(
+        model_type: ModelType,
+        load_params: LoadParams,
+        progress: &mut dyn FnMut(f32) -> bool,
+    ) -> Result<Self> {
+        LLM::load_with_progress(model_type, load_params, progress)
+    }
+
+    fn predict(&self, prompt: &str, inference_params: InferenceParams, callback: &mut dyn FnMut(&str)) -> Result<Completion> {
+        LLM::predict(self, prompt, inference_params, callback)
+    }
+
+    fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<u32>> {
+        LLM::tokenize(self, text, add_bos, parse_special)
+    }
+
+    fn embed(&self, inputs: &[&str], inference_params: InferenceParams) -> Result<Vec<Vec<f32>>> {
+        LLM::embed(self, inputs, inference_params)
+    }
+
+    fn chat_template(&self) -> Option<String> {
+        LLM::chat_template(self)
+    }
+
+    fn apply_chat_template(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        LLM::apply_chat_template(self, messages, add_generation_prompt)
+    }
+}
@@ -0,0 +1,240 @@
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::LlamaToken;
+use std::time::{Duration, Instant};
+
+use crate::completion::CancellationToken;
+use crate::embedding::MAX_SEQS_PER_BATCH;
+use crate::sampling::Sampler;
+use crate::session::Generation;
+use crate::{Completion, ContextOverflow, FinishReason, InferenceParams, LlmError, Result, LLM};
+
+/// One prompt of a batched prediction
+struct Sequence {
+    id: i32,
+    sampler: Sampler,
+    generation: Generation,
+    // Position of the next token to decode
+    n_past: i32,
+    // Token decoded in the next step, `None` once the sequence has finished
+    next: Option<LlamaToken>,
+}
+
+impl LLM {
+    /// Generates a completion for every prompt, decoding all of them together
+    /// in a single context.
+    ///
+    /// Each prompt runs as its own sequence with its own sampler, grammar
+    /// state and stop strings, and finishes independently of the others. The
+    /// sequences share the context, so `n_ctx` must hold every prompt plus
+    /// its generated tokens, and they draw from the same seeded RNG, so a
+    /// completion can depend on the other prompts in the batch. Completions
+    /// are returned in the order of `prompts`. More than 64 prompts are
+    /// decoded in consecutive groups of 64, each group on its own starting
+    /// from an empty context.
+    ///
+    /// When the context fills up, `ContextOverflow::Error` fails the whole
+    /// call and `ContextOverflow::Stop` ends the sequences that no longer fit
+    /// with `FinishReason::Length`. `ContextOverflow::Shift` is not supported.
+    pub fn predict_batch(&self, prompts: &[&str], inference_params: InferenceParams) -> Result<Vec<Completion>> {
+        if prompts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tokenized = prompts
+            .iter()
+            .map(|prompt| self.str_to_tokens(prompt, true, true))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(index) = tokenized.iter().position(Vec::is_empty) {
+            return Err(LlmError::InvalidRequest(format!("prompt {} is empty", index)));
+        }
+
+        if let ContextOverflow::Shift { .. } = inference_params.context_overflow {
+            return Err(LlmError::invalid_request("context shifting is not supported by predict_batch"));
+        }
+
+        let n_seq_max = prompts.len().min(MAX_SEQS_PER_BATCH);
+        let mut ctx = self.new_context_with_seqs(&inference_params, n_seq_max as u32)?;
+        let mut completions = Vec::with_capacity(prompts.len());
+        for group in tokenized.chunks(MAX_SEQS_PER_BATCH) {
+            let cancelled = inference_params
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled);
+            if cancelled {
+                // Groups that have not started are cancelled without evaluating their prompts
+                completions.extend(group.iter().map(|tokens| {
+                    let mut generation = Generation::cancelled(inference_params.max_tokens, &inference_params.stop);
+                    generation.record_prompt(tokens.len(), Duration::ZERO);
+                    generation.into_completion().0
+                }));
+                continue;
+            }
+            ctx.clear_kv_cache();
+            completions.extend(self.predict_group(&mut ctx, group, &inference_params)?);
+        }
+        Ok(completions)
+    }
+
+    /// Runs `predict_batch` for at most `MAX_SEQS_PER_BATCH` prompts in an empty context
+    fn predict_group(
+        &self,
+        ctx: &mut LlamaContext,
+        tokenized: &[Vec<LlamaToken>],
+        inference_params: &InferenceParams,
+    ) -> Result<Vec<Completion>> {
+        let n_batch = inference_params.n_batch.max(1) as usize;
+        let n_ctx = ctx.n_ctx() as usize;
+        let n_prompt: usize = tokenized.iter().map(Vec::len).sum();
+        if n_prompt > n_ctx {
+            return Err(LlmError::ContextOverflow {
+                n_tokens: n_prompt,
+                n_ctx: n_ctx as u32,
+            });
+        }
+
+        let mut sequences = Vec::with_capacity(tokenized.len());
+        for (id, tokens) in tokenized.iter().enumerate() {
+            sequences.push(Sequence {
+                id: id as i32,
+                sampler: Sampler::new(inference_params)?,
+                generation: Generation::new(inference_params.max_tokens, &inference_params.stop),
+                n_past: tokens.len() as i32 - 1,
+                next: tokens.last().copied(),
+            });
+        }
+
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let started = Instant::now();
+
+        // Prefill everything but the last prompt token of each sequence, that
+        // one is decoded by the first step so its logits are available to sample from
+        let prefill: Vec<(LlamaToken, i32, i32)> = tokenized
+            .iter()
+            .enumerate()
+            .flat_map(|(id, tokens)| {
+                tokens[..tokens.len() - 1]
+                    .iter()
+                    .enumerate()
+                    .map(move |(pos, token)| (*token, pos as i32, id as i32))
+            })
+            .collect();
+        for chunk in prefill.chunks(n_batch) {
+            batch.clear();
+            for (token, pos, id) in chunk {
+                batch.add(*token, *pos, &[*id], false)?;
+            }
+            ctx.decode(&mut batch)?;
+        }
+        let prompt_eval_time = started.elapsed();
+        // Cache cells in use by all sequences together
+        let mut n_cached = prefill.len();
+        for (sequence, tokens) in sequences.iter_mut().zip(&tokenized) {
+            sequence.generation.record_prompt(tokens.len(), prompt_eval_time);
+        }
+
+        loop {
+            let cancelled = inference_params
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled);
+            let active: Vec<usize> = sequences
+                .iter()
+                .enumerate()
+                .filter(|(_, sequence)| sequence.next.is_some())
+                .map(|(index, _)| index)
+                .collect();
+            if active.is_empty() {
+                break;
+            }
+            if cancelled {
+                for sequence in &mut sequences {
+                    sequence.generation.reached_limit(true);
+                }
+                break;
+            }
+
+            // Logits only survive until the next decode, so each chunk is sampled right away
+            for chunk in active.chunks(n_batch) {
+                let (fits, overflow) = chunk.split_at(chunk.len().min(n_ctx.saturating_sub(n_cached)));
+                if !overflow.is_empty() {
+                    if inference_params.context_overflow != ContextOverflow::Stop {
+                        return Err(LlmError::ContextOverflow {
+                            n_tokens: n_cached + chunk.len(),
+                            n_ctx: n_ctx as u32,
+                        });
+                    }
+                    for &index in overflow {
+                        let sequence = &mut sequences[index];
+                        sequence.generation.finish(FinishReason::Length);
+                        sequence.next = None;
+                        ctx.clear_kv_cache_seq(Some(sequence.id as u32), None, None);
+                        n_cached -= sequence.n_past as usize;
+                    }
+                }
+                if fits.is_empty() {
+                    continue;
+                }
+
+                batch.clear();
+                for &index in fits {
+                    let sequence = &sequences[index];
+                    batch.add(sequence.next.unwrap(), sequence.n_past, &[sequence.id], true)?;
+                }
+                ctx.decode(&mut batch)?;
+                n_cached += fits.len();
+
+                for (batch_idx, &index) in fits.iter().enumerate() {
+                    let sequence = &mut sequences[index];
+                    sequence.n_past += 1;
+                    sequence.next = self.sample_sequence(ctx, sequence, batch_idx as i32, inference_params)?;
+                    if sequence.next.is_none() {
+                        // Free the cache cells of finished sequences for the ones still running
+                        ctx.clear_kv_cache_seq(Some(sequence.id as u32), None, None);
+                        n_cached -= sequence.n_past as usize;
+                    }
+                }
+            }
+        }
+
+        Ok(sequences
+            .into_iter()
+            .map(|sequence| sequence.generation.into_completion().0)
+            .collect())
+    }
+
+    /// Samples the next token of `sequence` from the logits at `batch_idx`,
+    /// returning `None` once the sequence has finished
+    fn sample_sequence(
+        &self,
+        ctx: &mut LlamaContext,
+        sequence: &mut Sequence,
+        batch_idx: i32,
+        inference_params: &InferenceParams,
+    ) -> Result<Option<LlamaToken>> {
+        let generation = &mut sequence.generation;
+        if generation.reached_limit(false) {
+            return Ok(None);
+        }
+
+        let candidates = LlamaTokenDataArray::from_iter(ctx.candidates_ith(batch_idx), false);
+        let token = sequence.sampler.sample(ctx, candidates);
+        sequence.sampler.accept(ctx, token);
+
+        if self.model.is_eog_token(token) {
+            generation.finish(FinishReason::Stop);
+            return Ok(None);
+        }
+
+        let logprob = match inference_params.logprobs {
+            Some(top_n) => Some(self.token_logprob(ctx.get_logits_ith(batch_idx), token, top_n)?),
+            None => None,
+        };
+        generation.push(self, token, logprob)?;
+        // A matched stop string or the last allowed token ends the sequence without decoding it
+        if generation.reached_limit(false) {
+            return Ok(None);
+        }
+        Ok(Some(token))
+    }
+}
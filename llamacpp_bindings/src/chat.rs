@@ -0,0 +1,57 @@
+use llama_cpp_2::model::LlamaChatMessage;
+
+use crate::error::LlamaResultExt;
+use crate::{LlmError, Result, LLM};
+
+const CHAT_TEMPLATE_KEY: &str = "tokenizer.chat_template";
+
+/// A single turn of a conversation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// Usually `system`, `user` or `assistant`
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+impl LLM {
+    /// Chat template used by `apply_chat_template`, if the model or `LoadParams` provides one
+    pub fn chat_template(&self) -> Option<String> {
+        self.chat_template
+            .clone()
+            .or_else(|| self.model.meta_val_str(CHAT_TEMPLATE_KEY).ok())
+    }
+
+    /// Formats `messages` into a prompt using the model's chat template.
+    ///
+    /// The template comes from `LoadParams::chat_template` when set, and
+    /// from the `tokenizer.chat_template` GGUF metadata otherwise. Rendering
+    /// is done by llama.cpp, which recognises the common template families
+    /// (Llama 3, ChatML, Mistral, Gemma, ...) from the template text; a
+    /// family name such as `llama3` or `chatml` can also be used as the
+    /// override. With `add_generation_prompt` the prompt ends with the
+    /// header of an assistant turn, ready to be passed to `predict`.
+    pub fn apply_chat_template(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let template = self
+            .chat_template()
+            .ok_or_else(|| LlmError::invalid_request("the model has no chat template and none was configured"))?;
+
+        let chat = messages
+            .iter()
+            .map(|message| LlamaChatMessage::new(message.role.clone(), message.content.clone()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| LlmError::invalid_request("chat messages must not contain null bytes"))?;
+
+        self.model
+            .apply_chat_template(Some(template), chat, add_generation_prompt)
+            .llama_context(|| "unable to apply the chat template".to_string())
+    }
+}
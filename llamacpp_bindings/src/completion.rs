@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::logprobs::TokenLogprob;
+
+/// Result of a prediction
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    /// Generated text, without any matched stop string
+    pub text: String,
+    pub finish_reason: FinishReason,
+    /// One entry per generated token when `InferenceParams::logprobs` is set
+    pub logprobs: Vec<TokenLogprob>,
+    /// One entry per prompt token after the first when `InferenceParams::echo` is set
+    pub prompt_logprobs: Vec<TokenLogprob>,
+    pub stats: GenerationStats,
+}
+
+/// Token counts and timings of a prediction
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GenerationStats {
+    /// Tokens in the prompt, including any reused from a session's KV cache
+    pub n_prompt_tokens: usize,
+    pub n_generated_tokens: usize,
+    /// Time spent evaluating the prompt
+    pub prompt_eval_time: Duration,
+    /// Time spent sampling and decoding the generated tokens
+    pub generation_time: Duration,
+    /// Time from the start of prompt evaluation until the first generated token, zero when nothing was generated
+    pub time_to_first_token: Duration,
+    /// Generated tokens per second of `generation_time`
+    pub tokens_per_second: f32,
+}
+
+/// Why generation ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FinishReason {
+    /// An end-of-generation token or a stop string was produced
+    #[default]
+    Stop,
+    /// `max_tokens` was reached
+    Length,
+    /// The cancellation token was triggered
+    Cancelled,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Shared flag that stops a running generation.
+///
+/// Clones share the same flag, so a token can be kept by the caller and
+/// another clone passed in `InferenceParams::cancel`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the generation to stop before its next decode step
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,118 @@
+use llama_cpp_2::context::params::LlamaPoolingType;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
+
+use crate::error::LlamaResultExt;
+use crate::{InferenceParams, LlmError, Result, LLM};
+
+// Most sequences decoded together, llama.cpp rejects sequence ids from `LLAMA_MAX_SEQ` (64) on
+pub(crate) const MAX_SEQS_PER_BATCH: usize = 64;
+
+/// How token embeddings are combined into a single sentence embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// Use the pooling type stored in the model metadata
+    #[default]
+    Model,
+    /// Average of all token embeddings
+    Mean,
+    /// Embedding of the first token
+    Cls,
+    /// Embedding of the last token
+    Last,
+}
+
+impl From<Pooling> for LlamaPoolingType {
+    fn from(pooling: Pooling) -> Self {
+        match pooling {
+            Pooling::Model => LlamaPoolingType::Unspecified,
+            Pooling::Mean => LlamaPoolingType::Mean,
+            Pooling::Cls => LlamaPoolingType::Cls,
+            Pooling::Last => LlamaPoolingType::Last,
+        }
+    }
+}
+
+impl LLM {
+    /// Computes one embedding per input.
+    ///
+    /// Inputs are packed into as few decode calls as `n_batch` allows, each
+    /// one as its own sequence and at most 64 per call. Every input must fit
+    /// in a single batch.
+    pub fn embed(&self, inputs: &[&str], inference_params: InferenceParams) -> Result<Vec<Vec<f32>>> {
+        let mut params = inference_params;
+        params.embeddings = true;
+        // Non-causal models need a whole sequence in one physical batch
+        params.n_ubatch = params.n_batch;
+        let n_batch = params.n_batch as usize;
+        let normalize = params.normalize_embeddings;
+
+        let n_seq_max = inputs.len().clamp(1, MAX_SEQS_PER_BATCH);
+        let mut ctx = self.new_context_with_seqs(&params, n_seq_max as u32)?;
+
+        let tokenized = inputs
+            .iter()
+            .map(|input| self.str_to_tokens(input, true, true))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        let mut n_seq = 0;
+
+        for (index, tokens) in tokenized.iter().enumerate() {
+            if tokens.len() > n_batch {
+                return Err(LlmError::InvalidRequest(format!(
+                    "input {} has {} tokens which exceeds the batch size of {}",
+                    index,
+                    tokens.len(),
+                    n_batch
+                )));
+            }
+            if batch.n_tokens() as usize + tokens.len() > n_batch || n_seq as usize == n_seq_max {
+                decode_embeddings(&mut ctx, &mut batch, n_seq, normalize, &mut embeddings)?;
+                n_seq = 0;
+            }
+            batch.add_sequence(tokens, n_seq, false)?;
+            n_seq += 1;
+        }
+        if n_seq > 0 {
+            decode_embeddings(&mut ctx, &mut batch, n_seq, normalize, &mut embeddings)?;
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// Decodes a batch holding `n_seq` sequences and collects their pooled embeddings
+fn decode_embeddings(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    n_seq: i32,
+    normalize: bool,
+    output: &mut Vec<Vec<f32>>,
+) -> Result<()> {
+    // Sequences from the previous batch must not attend to this one
+    ctx.clear_kv_cache();
+    ctx.decode(batch)?;
+
+    for seq in 0..n_seq {
+        let embedding = ctx
+            .embeddings_seq_ith(seq)
+            .llama_context(|| "model produced no pooled embeddings, choose an explicit pooling type".to_string())?;
+        let mut embedding = embedding.to_vec();
+        if normalize {
+            l2_normalize(&mut embedding);
+        }
+        output.push(embedding);
+    }
+
+    batch.clear();
+    Ok(())
+}
+
+fn l2_normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
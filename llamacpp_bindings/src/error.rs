@@ -0,0 +1,168 @@
+use llama_cpp_2::llama_batch::BatchAddError;
+use llama_cpp_2::{DecodeError, LlamaContextLoadError, StringToTokenError, TokenToStringError};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Error type of the source errors wrapped by `LlmError`
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+pub type Result<T, E = LlmError> = std::result::Result<T, E>;
+
+/// Errors returned by `llamacpp_bindings`.
+///
+/// Variants separate failures the caller can act on differently, e.g. a
+/// missing file from a corrupt one or a bad request from a full context.
+#[derive(Debug, Error)]
+pub enum LlmError {
+    /// A model, adapter or snapshot file does not exist
+    #[error("unable to read {}", path.display())]
+    FileNotFound {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A file exists but could not be opened or read, or it changed while it was read
+    #[error("failed reading {}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A file could not be written
+    #[error("unable to write {}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The model file does not have the expected SHA-256 digest
+    #[error("{} has SHA-256 {actual} but {expected} was expected", path.display())]
+    DigestMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// llama.cpp rejected a model or adapter file, usually a corrupt or unsupported GGUF
+    #[error("{} is not a valid GGUF file for this operation", path.display())]
+    InvalidModel {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    /// A HuggingFace file is not cached in offline mode or could not be downloaded
+    #[error("unable to fetch {repo}/{file} at revision {revision}: {reason}")]
+    Download {
+        repo: String,
+        file: String,
+        revision: String,
+        reason: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+    /// The tokens do not fit in the context
+    #[error("context overflow: {n_tokens} tokens do not fit in n_ctx of {n_ctx}")]
+    ContextOverflow { n_tokens: usize, n_ctx: u32 },
+    /// Text could not be converted to tokens or back
+    #[error("failed to tokenize text")]
+    Tokenize(#[source] BoxError),
+    /// llama.cpp could not allocate a context or its compute buffers
+    #[error("out of memory: {0}")]
+    OutOfMemory(String),
+    /// A session snapshot is corrupt or was taken with a different model
+    #[error("invalid session snapshot: {0}")]
+    InvalidState(String),
+    /// Loading was aborted by the progress callback
+    #[error("loading was aborted")]
+    Aborted,
+    /// The cancellation token fired while a prompt was evaluated
+    #[error("prompt evaluation was cancelled")]
+    Cancelled,
+    /// The request itself is invalid, e.g. an empty prompt, a bad grammar or an unknown adapter
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// Any other failure reported by llama.cpp
+    #[error("{message}")]
+    Llama {
+        message: String,
+        #[source]
+        source: Option<BoxError>,
+    },
+}
+
+impl LlmError {
+    pub(crate) fn invalid_request(message: impl Into<String>) -> Self {
+        LlmError::InvalidRequest(message.into())
+    }
+
+    /// `FileNotFound` when `path` does not exist, `Read` for any other I/O failure such as a permission error
+    pub(crate) fn read(path: &Path, source: std::io::Error) -> Self {
+        let path = path.to_path_buf();
+        if source.kind() == std::io::ErrorKind::NotFound {
+            LlmError::FileNotFound { path, source }
+        } else {
+            LlmError::Read { path, source }
+        }
+    }
+
+    pub(crate) fn llama(message: impl Into<String>) -> Self {
+        LlmError::Llama {
+            message: message.into(),
+            source: None,
+        }
+    }
+}
+
+impl From<StringToTokenError> for LlmError {
+    fn from(error: StringToTokenError) -> Self {
+        LlmError::Tokenize(Box::new(error))
+    }
+}
+
+impl From<TokenToStringError> for LlmError {
+    fn from(error: TokenToStringError) -> Self {
+        LlmError::Tokenize(Box::new(error))
+    }
+}
+
+impl From<LlamaContextLoadError> for LlmError {
+    fn from(_: LlamaContextLoadError) -> Self {
+        // llama.cpp only fails to create a context when its buffers cannot be allocated
+        LlmError::OutOfMemory("unable to create the llama_context".to_string())
+    }
+}
+
+impl From<DecodeError> for LlmError {
+    fn from(error: DecodeError) -> Self {
+        // No KV cache slot means the batch does not fit in this context, a retry would fail the same way
+        LlmError::Llama {
+            message: "failed to decode tokens".to_string(),
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+impl From<BatchAddError> for LlmError {
+    fn from(error: BatchAddError) -> Self {
+        LlmError::Llama {
+            message: "unable to add tokens to the batch".to_string(),
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+/// Attaches a message to llama.cpp errors that have no dedicated variant
+pub(crate) trait LlamaResultExt<T> {
+    fn llama_context(self, message: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T, E> LlamaResultExt<T> for std::result::Result<T, E>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn llama_context(self, message: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|error| LlmError::Llama {
+            message: message(),
+            source: Some(Box::new(error)),
+        })
+    }
+}
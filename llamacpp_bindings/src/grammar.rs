@@ -0,0 +1,361 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{LlmError, Result};
+
+/// Returns an `LlmError::InvalidRequest` describing what is wrong with the schema
+macro_rules! invalid_schema {
+    ($($arg:tt)*) => {
+        return Err(LlmError::InvalidRequest(format!($($arg)*)))
+    };
+}
+
+// Largest `minItems` accepted, every required element is a separate term of the array rule
+const MAX_MIN_ITEMS: u64 = 1024;
+
+// Shared rules for JSON values. Whitespace is limited to a single character
+// between tokens so a model cannot stall the output with endless padding,
+// and control characters must be escaped inside strings as JSON requires.
+const PRIMITIVE_RULES: &[(&str, &str)] = &[
+    ("ws", r#"[ \t\n]?"#),
+    (
+        "string",
+        r#""\"" ( [^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]) )* "\"" ws"#,
+    ),
+    ("number", r#""-"? ([0-9] | [1-9] [0-9]*) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws"#),
+    ("integer", r#""-"? ([0-9] | [1-9] [0-9]*) ws"#),
+    ("boolean", r#"("true" | "false") ws"#),
+    ("null", r#""null" ws"#),
+    ("value", r#"object | array | string | number | boolean | null"#),
+    ("object", r#""{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws"#),
+    ("array", r#""[" ws ( value ( "," ws value )* )? "]" ws"#),
+];
+
+/// Compiles a JSON Schema into a GBNF grammar for `InferenceParams::grammar`.
+///
+/// Supported keywords are `type` (including lists of types), `properties`,
+/// `required`, `items`, `minItems`, `enum`, `const`, `anyOf` and `oneOf`.
+/// Object properties are generated in the order they are declared, with
+/// optional properties allowed to be left out. `$ref` is not supported.
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = SchemaConverter::default();
+    let root = converter.visit(schema, "root")?;
+
+    let mut grammar = format!("root ::= {}\n", root);
+    for (name, body) in &converter.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    for (name, body) in PRIMITIVE_RULES {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    Ok(grammar)
+}
+
+#[derive(Default)]
+struct SchemaConverter {
+    rules: BTreeMap<String, String>,
+}
+
+impl SchemaConverter {
+    /// Returns a grammar expression matching `schema`, adding named rules for nested schemas
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => invalid_schema!("schema `{}` does not accept any value", name),
+            Value::Object(schema) => schema,
+            _ => invalid_schema!("schema `{}` must be an object", name),
+        };
+
+        if schema.contains_key("$ref") {
+            invalid_schema!("schema `{}` uses `$ref`, which is not supported", name);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal_value(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array().filter(|values| !values.is_empty()) else {
+                invalid_schema!("`enum` in schema `{}` must be a non-empty array", name);
+            };
+            let alternatives: Vec<String> = values.iter().map(literal_value).collect();
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(variants) = schema.get(keyword).and_then(Value::as_array) {
+                let alternatives = variants
+                    .iter()
+                    .enumerate()
+                    .map(|(i, variant)| self.visit_named(variant, &format!("{}-{}", name, i)))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(format!("( {} )", alternatives.join(" | ")));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.visit_type(schema, ty, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|ty| match ty.as_str() {
+                        Some(ty) => self.visit_type(schema, ty, &format!("{}-{}", name, ty)),
+                        None => invalid_schema!("`type` in schema `{}` must only contain strings", name),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("( {} )", alternatives.join(" | ")))
+            }
+            Some(_) => invalid_schema!("`type` in schema `{}` must be a string or an array", name),
+            None if schema.contains_key("properties") => self.visit_type(schema, "object", name),
+            None if schema.contains_key("items") => self.visit_type(schema, "array", name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    /// Like `visit`, but stores the expression as a rule and returns its name
+    fn visit_named(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let expression = self.visit(schema, name)?;
+        // Different names can map to the same rule name, e.g. properties `a_b` and `a-b`
+        let base = rule_name(name);
+        let mut rule = base.clone();
+        let mut suffix = 1;
+        while self.rules.contains_key(&rule) {
+            rule = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+        self.rules.insert(rule.clone(), expression);
+        Ok(rule)
+    }
+
+    fn visit_type(&mut self, schema: &Map<String, Value>, ty: &str, name: &str) -> Result<String> {
+        match ty {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.to_string()),
+            _ => invalid_schema!("unsupported type `{}` in schema `{}`", ty, name),
+        }
+    }
+
+    fn visit_object(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok("object".to_string());
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if let Some(missing) = required.iter().find(|key| !properties.contains_key(**key)) {
+            invalid_schema!("required property `{}` is not declared in schema `{}`", missing, name);
+        }
+
+        let mut members = Vec::new();
+        for (key, property) in properties {
+            let value = self.visit_named(property, &format!("{}-{}", name, key))?;
+            let member = format!("{} ws \":\" ws {}", literal(&Value::String(key.clone()).to_string()), value);
+            members.push((member, required.contains(&key.as_str())));
+        }
+
+        // The members that may come first, and whether all of them may be left out
+        let (alternatives, nullable) = leading_members(&members);
+        let members = match alternatives.as_slice() {
+            [] => String::new(),
+            [only] if !nullable => only.clone(),
+            _ if nullable => format!("( {} )?", alternatives.join(" | ")),
+            _ => format!("( {} )", alternatives.join(" | ")),
+        };
+
+        Ok(format!("\"{{\" ws {} \"}}\" ws", members))
+    }
+
+    fn visit_array(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit_named(items, &format!("{}-item", name))?,
+            None => "value".to_string(),
+        };
+        let min_items = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        if min_items > MAX_MIN_ITEMS {
+            invalid_schema!("`minItems` in schema `{}` is larger than {}", name, MAX_MIN_ITEMS);
+        }
+        // The first `minItems` elements are spelled out, any further ones repeat
+        let mut elements = vec![item.clone(); min_items.max(1) as usize].join(" \",\" ws ");
+        elements.push_str(&format!(" ( \",\" ws {} )*", item));
+        if min_items > 0 {
+            Ok(format!("\"[\" ws {} \"]\" ws", elements))
+        } else {
+            Ok(format!("\"[\" ws ( {} )? \"]\" ws", elements))
+        }
+    }
+}
+
+/// Alternatives for `members` in declared order when no member precedes them,
+/// and whether every member may be left out.
+///
+/// Each alternative starts with a different member that is present, all
+/// optional members before it being absent, so only members that follow
+/// another one get a leading comma.
+fn leading_members(members: &[(String, bool)]) -> (Vec<String>, bool) {
+    let Some(((member, required), rest)) = members.split_first() else {
+        return (Vec::new(), true);
+    };
+    let mut present = member.clone();
+    for (member, required) in rest {
+        if *required {
+            present.push_str(&format!(" \",\" ws {}", member));
+        } else {
+            present.push_str(&format!(" ( \",\" ws {} )?", member));
+        }
+    }
+    if *required {
+        return (vec![present], false);
+    }
+    let (mut alternatives, nullable) = leading_members(rest);
+    alternatives.insert(0, present);
+    (alternatives, nullable)
+}
+
+/// Rule names may only contain letters, digits and dashes
+fn rule_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// Matches the compact JSON encoding of `value` exactly
+fn literal_value(value: &Value) -> String {
+    format!("{} ws", literal(&value.to_string()))
+}
+
+/// Quotes `text` as a GBNF string literal
+fn literal(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use llama_cpp_2::grammar::LlamaGrammar;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    use super::json_schema_to_grammar;
+    use crate::LlmError;
+
+    fn has_rule(grammar: &str, rule: &str) -> bool {
+        grammar.lines().any(|line| line == rule)
+    }
+
+    #[test]
+    fn primitive_type_uses_shared_rule() {
+        let grammar = json_schema_to_grammar(&json!({ "type": "string" })).unwrap();
+        assert!(grammar.starts_with("root ::= string\n"));
+        assert!(grammar.lines().any(|line| line.starts_with("string ::= ")));
+    }
+
+    #[test]
+    fn strings_exclude_control_characters() {
+        let grammar = json_schema_to_grammar(&json!({ "type": "string" })).unwrap();
+        let string = grammar.lines().find(|line| line.starts_with("string ::= ")).unwrap();
+        assert!(string.contains(r#"[^"\\\x7F\x00-\x1F]"#), "{}", string);
+    }
+
+    #[test]
+    fn object_members_keep_declared_order() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "b": { "type": "integer" }, "a": { "type": "string" } },
+            "required": ["a"],
+        });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(has_rule(
+            &grammar,
+            r#"root ::= "{" ws ( "\"b\"" ws ":" ws root-b "," ws "\"a\"" ws ":" ws root-a | "\"a\"" ws ":" ws root-a ) "}" ws"#
+        ));
+        assert!(has_rule(&grammar, "root-a ::= string"));
+        assert!(has_rule(&grammar, "root-b ::= integer"));
+    }
+
+    #[test]
+    fn optional_members_may_all_be_left_out() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "a": { "type": "string" }, "b": { "type": "string" } },
+        });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(has_rule(
+            &grammar,
+            r#"root ::= "{" ws ( "\"a\"" ws ":" ws root-a ( "," ws "\"b\"" ws ":" ws root-b )? | "\"b\"" ws ":" ws root-b )? "}" ws"#
+        ));
+    }
+
+    #[test]
+    fn colliding_rule_names_get_a_suffix() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "a_b": { "type": "string" }, "a-b": { "type": "integer" } },
+        });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(has_rule(&grammar, "root-a-b ::= string"));
+        assert!(has_rule(&grammar, "root-a-b-1 ::= integer"));
+    }
+
+    #[test]
+    fn min_items_are_spelled_out() {
+        let schema = json!({ "type": "array", "items": { "type": "string" }, "minItems": 2 });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(has_rule(
+            &grammar,
+            r#"root ::= "[" ws root-item "," ws root-item ( "," ws root-item )* "]" ws"#
+        ));
+
+        let schema = json!({ "type": "array", "items": { "type": "string" } });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(has_rule(
+            &grammar,
+            r#"root ::= "[" ws ( root-item ( "," ws root-item )* )? "]" ws"#
+        ));
+    }
+
+    #[test]
+    fn enum_values_are_literals() {
+        let grammar = json_schema_to_grammar(&json!({ "enum": ["a", 1] })).unwrap();
+        assert!(has_rule(&grammar, r#"root ::= ( "\"a\"" ws | "1" ws )"#));
+    }
+
+    #[test]
+    fn rejects_unsupported_schemas() {
+        for schema in [
+            json!({ "$ref": "#/definitions/item" }),
+            json!({ "type": "object", "properties": {}, "required": ["missing"] }),
+            json!({ "type": "tuple" }),
+            json!({ "enum": [] }),
+            json!(false),
+        ] {
+            assert!(matches!(json_schema_to_grammar(&schema), Err(LlmError::InvalidRequest(_))), "{}", schema);
+        }
+    }
+
+    #[test]
+    fn generated_grammar_parses() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "minItems": 1 },
+                "score": { "anyOf": [{ "type": "number" }, { "type": "null" }] },
+            },
+            "required": ["name"],
+        });
+        let grammar = json_schema_to_grammar(&schema).unwrap();
+        assert!(LlamaGrammar::from_str(&grammar).is_ok(), "{}", grammar);
+    }
+}
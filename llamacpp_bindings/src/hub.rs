@@ -0,0 +1,82 @@
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use std::path::PathBuf;
+
+use crate::{LlmError, Result};
+
+const DEFAULT_REVISION: &str = "main";
+
+/// Resolves a file from a HuggingFace model repository to a local path.
+///
+/// Files are looked up in `cache_dir` (or the default HuggingFace cache when
+/// `None`) using the same layout as `huggingface-cli`, so a cache populated on
+/// another machine can be copied into an image as is. When `offline` is set
+/// the network is never touched and a missing file is an error.
+pub(crate) fn resolve(
+    repo: &str,
+    file: &str,
+    revision: Option<&str>,
+    cache_dir: Option<PathBuf>,
+    offline: bool,
+) -> Result<PathBuf> {
+    let revision = revision.unwrap_or(DEFAULT_REVISION);
+    let cache = match &cache_dir {
+        Some(dir) => Cache::new(dir.clone()),
+        None => Cache::default(),
+    };
+
+    if let Some(path) = find_cached(&cache, repo, file, revision) {
+        return Ok(path);
+    }
+
+    let download_error = |reason: String, source: Option<hf_hub::api::sync::ApiError>| LlmError::Download {
+        repo: repo.to_string(),
+        file: file.to_string(),
+        revision: revision.to_string(),
+        reason,
+        source: source.map(|source| source.into()),
+    };
+
+    if offline {
+        return Err(download_error(
+            format!(
+                "not in the HuggingFace cache at {} and offline mode is enabled",
+                cache.path().display()
+            ),
+            None,
+        ));
+    }
+
+    let mut builder = ApiBuilder::new().with_progress(false);
+    if let Some(dir) = cache_dir {
+        builder = builder.with_cache_dir(dir);
+    }
+    let api = builder
+        .build()
+        .map_err(|e| download_error("unable to create the HuggingFace API client".to_string(), Some(e)))?;
+
+    api.repo(model_repo(repo, revision))
+        .get(file)
+        .map_err(|e| download_error("download failed".to_string(), Some(e)))
+}
+
+fn model_repo(repo: &str, revision: &str) -> Repo {
+    Repo::with_revision(repo.to_string(), RepoType::Model, revision.to_string())
+}
+
+/// Looks a file up in the cache without any network access
+fn find_cached(cache: &Cache, repo: &str, file: &str, revision: &str) -> Option<PathBuf> {
+    // Branch and tag names are mapped to a commit through `refs/`
+    if let Some(path) = cache.repo(model_repo(repo, revision)).get(file) {
+        return Some(path);
+    }
+
+    // Commit hashes have no ref file, their snapshot directory is named after them
+    let snapshot = cache
+        .path()
+        .join(format!("models--{}", repo.replace('/', "--")))
+        .join("snapshots")
+        .join(revision)
+        .join(file);
+    snapshot.exists().then_some(snapshot)
+}
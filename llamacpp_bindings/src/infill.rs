@@ -0,0 +1,82 @@
+use llama_cpp_2::model::AddBos;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::{Completion, InferenceParams, LlmError, Result, LLM};
+
+// GGUF keys of the prefix, suffix and middle token ids, newer llama.cpp conversions first
+const FIM_TOKEN_KEYS: [[&str; 3]; 2] = [
+    [
+        "tokenizer.ggml.fim_pre_token_id",
+        "tokenizer.ggml.fim_suf_token_id",
+        "tokenizer.ggml.fim_mid_token_id",
+    ],
+    [
+        "tokenizer.ggml.prefix_token_id",
+        "tokenizer.ggml.suffix_token_id",
+        "tokenizer.ggml.middle_token_id",
+    ],
+];
+
+// Spellings of the FIM tokens used by models that do not declare them: CodeLlama, Qwen, StarCoder
+const FIM_TOKEN_TEXTS: [[&str; 3]; 3] = [
+    ["<PRE>", "<SUF>", "<MID>"],
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+];
+
+impl LLM {
+    /// Generates the text that belongs between `prefix` and `suffix`.
+    ///
+    /// The prompt is built in prefix-suffix-middle order from the model's
+    /// fill-in-the-middle tokens, `<PRE>`, `<SUF>` and `<MID>` or the
+    /// equivalents declared in the GGUF metadata, and generation otherwise
+    /// works like `predict`. Fails with `LlmError::InvalidRequest` when the
+    /// model has no such tokens, i.e. it was not trained for infilling.
+    pub fn infill<F>(&self, prefix: &str, suffix: &str, inference_params: InferenceParams, callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        let [pre, suf, mid] = self
+            .fim_tokens()
+            .ok_or_else(|| LlmError::invalid_request("the model has no fill-in-the-middle tokens"))?;
+
+        // BOS is always added, like for the prompts of `predict`
+        let mut tokens = vec![self.model.token_bos(), pre];
+        tokens.extend(self.str_to_tokens(prefix, false, false)?);
+        tokens.push(suf);
+        tokens.extend(self.str_to_tokens(suffix, false, false)?);
+        tokens.push(mid);
+
+        self.session(inference_params)?.predict_tokens(tokens, callback)
+    }
+
+    /// Prefix, suffix and middle tokens, if the model has all three
+    fn fim_tokens(&self) -> Option<[LlamaToken; 3]> {
+        let n_vocab = self.model.n_vocab();
+        let declared = FIM_TOKEN_KEYS.iter().find_map(|keys| {
+            let mut tokens = [LlamaToken::new(0); 3];
+            for (token, key) in tokens.iter_mut().zip(keys) {
+                let id: i32 = self.model.meta_val_str(key).ok()?.parse().ok()?;
+                // Conversions write -1 for tokens the model does not have
+                if !(0..n_vocab).contains(&id) {
+                    return None;
+                }
+                *token = LlamaToken::new(id);
+            }
+            Some(tokens)
+        });
+        declared.or_else(|| {
+            FIM_TOKEN_TEXTS.iter().find_map(|texts| {
+                let mut tokens = [LlamaToken::new(0); 3];
+                for (token, text) in tokens.iter_mut().zip(texts) {
+                    // Only a vocabulary entry for the whole spelling tokenizes to a single token
+                    match self.model.str_to_token(text, AddBos::Never).ok()?.as_slice() {
+                        [single] => *token = *single,
+                        _ => return None,
+                    }
+                }
+                Some(tokens)
+            })
+        })
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use crate::LLM;
+
+/// Details about a loaded model, read from its GGUF metadata
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    /// Value of `general.architecture`, e.g. `llama`
+    pub architecture: String,
+    /// Value of `general.name`, if present
+    pub name: Option<String>,
+    pub n_params: u64,
+    /// Context length the model was trained with
+    pub n_ctx_train: u32,
+    pub n_embd: u32,
+    pub n_layer: u32,
+    pub n_vocab: u32,
+    /// Quantization type derived from `general.file_type`, e.g. `Q4_K_M`
+    pub quantization: String,
+    /// Size of the GGUF file in bytes
+    pub file_size: u64,
+    /// Lowercase hex SHA-256 digest of the GGUF file
+    pub sha256: String,
+    /// Every scalar GGUF key/value pair, arrays such as the vocabulary are not included
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl LLM {
+    /// Collects the model's properties and metadata
+    pub fn info(&self) -> ModelInfo {
+        let metadata = self.metadata();
+        let architecture = metadata
+            .get("general.architecture")
+            .cloned()
+            .unwrap_or_else(|| "unknown".to_string());
+        let n_layer = metadata
+            .get(&format!("{}.block_count", architecture))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        let quantization = metadata
+            .get("general.file_type")
+            .and_then(|value| value.parse().ok())
+            .map(file_type_name)
+            .unwrap_or_else(|| "unknown".to_string());
+
+        ModelInfo {
+            name: metadata.get("general.name").cloned(),
+            architecture,
+            n_params: self.model.n_params(),
+            n_ctx_train: self.model.n_ctx_train(),
+            n_embd: self.model.n_embd() as u32,
+            n_layer,
+            n_vocab: self.model.n_vocab() as u32,
+            quantization,
+            file_size: self.file_size,
+            sha256: self.sha256.clone(),
+            metadata,
+        }
+    }
+
+    /// Reads all GGUF key/value pairs exposed by llama.cpp
+    fn metadata(&self) -> BTreeMap<String, String> {
+        (0..self.model.meta_count())
+            .filter_map(|i| {
+                let key = self.model.meta_key_by_index(i).ok()?;
+                let value = self.model.meta_val_str_by_index(i).ok()?;
+                Some((key, value))
+            })
+            .collect()
+    }
+}
+
+/// Name of a `llama_ftype` value as used in GGUF file names
+fn file_type_name(file_type: u32) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        33 => "Q4_0_4_4",
+        34 => "Q4_0_4_8",
+        35 => "Q4_0_8_8",
+        36 => "TQ1_0",
+        37 => "TQ2_0",
+        other => return format!("unknown ({})", other),
+    };
+    name.to_string()
+}
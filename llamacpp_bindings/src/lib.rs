@@ -1,16 +1,52 @@
-use anyhow::{ Context, Result};
 use llama_cpp_2::llama_backend::LlamaBackend;
-use llama_cpp_2::model::{LlamaModel, params::LlamaModelParams, Special, AddBos};
-use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::context::params::LlamaContextParams;
-use llama_cpp_2::llama_batch::LlamaBatch;
 use std::num::NonZeroU32;
 use std::path::PathBuf;
-use encoding_rs::UTF_8;
-use hf_hub::api::sync::ApiBuilder;
-use llama_cpp_2::ggml_time_us;
-use std::time::Duration;
 use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::error::LlamaResultExt;
+
+mod backend;
+mod chat;
+mod batch;
+mod completion;
+mod embedding;
+mod error;
+mod grammar;
+mod hub;
+mod info;
+mod infill;
+mod load;
+mod logprobs;
+mod lora;
+mod mock;
+mod sampling;
+mod score;
+mod session;
+mod speculative;
+mod state;
+mod stop;
+mod stream;
+mod tokenizer;
+
+pub use backend::TextGenerator;
+pub use chat::ChatMessage;
+pub use completion::{CancellationToken, Completion, FinishReason, GenerationStats};
+pub use embedding::Pooling;
+pub use error::{BoxError, LlmError, Result};
+pub use grammar::json_schema_to_grammar;
+pub use info::ModelInfo;
+pub use load::KvOverride;
+pub use logprobs::{TokenLogprob, TopLogprob};
+pub use mock::MockBackend;
+pub use score::{Perplexity, Score};
+pub use session::{ContextOverflow, LlmSession};
+pub use speculative::SpeculativeStats;
+pub use stream::{TokenEvent, TokenStream};
 
 static BACKEND: OnceCell<LlamaBackend> = OnceCell::new();
 
@@ -18,7 +54,8 @@ static BACKEND: OnceCell<LlamaBackend> = OnceCell::new();
 #[derive(Debug, Clone)]
 pub enum ModelType {
     Local { path: PathBuf },
-    // HuggingFace { repo: String, model: String },
+    /// A file in a HuggingFace model repository, `revision` defaults to `main`
+    HuggingFace { repo: String, file: String, revision: Option<String> },
 }
 
 /// High-level handle for model parameters
@@ -28,7 +65,16 @@ pub struct LoadParams {
     pub use_mmap: bool,
     pub use_mlock: bool,
     pub vocab_only: bool,
-    // pub kv_overrides: Vec<(String, ParamOverrideValue)>,
+    /// HuggingFace cache used to resolve `ModelType::HuggingFace`, `None` uses the default cache
+    pub hf_cache_dir: Option<PathBuf>,
+    /// Only resolve HuggingFace files that are already in the cache
+    pub hf_offline: bool,
+    /// Chat template used instead of the one stored in the GGUF file
+    pub chat_template: Option<String>,
+    /// GGUF metadata entries replaced at load time, e.g. `("llama.context_length", KvOverride::Int(8192))`
+    pub kv_overrides: Vec<(String, KvOverride)>,
+    /// Hex SHA-256 digest the model file must have, loading fails on a mismatch. Disables `use_mmap`
+    pub expected_sha256: Option<String>,
 }
 
 impl Default for LoadParams {
@@ -38,7 +84,11 @@ impl Default for LoadParams {
             use_mmap: true,
             use_mlock: false,
             vocab_only: false,
-            // kv_overrides: Vec::new(),
+            hf_cache_dir: None,
+            hf_offline: false,
+            chat_template: None,
+            kv_overrides: Vec::new(),
+            expected_sha256: None,
         }
     }
 }
@@ -47,151 +97,214 @@ impl Default for LoadParams {
 #[derive(Debug, Clone)]
 pub struct InferenceParams {
     pub n_ctx: NonZeroU32,
+    /// Maximum number of tokens submitted to a single decode call, longer prompts are split into chunks
+    pub n_batch: u32,
+    /// Physical batch size used by llama.cpp inside each decode call, at most `n_batch`
+    pub n_ubatch: u32,
     pub n_threads: Option<i32>,
     pub n_threads_batch: Option<i32>,
     pub seed: u32,
     pub max_tokens: i32,
-    // pub temperature: f32, No support for temperature in llama_cpp_2
+    /// Sampling temperature, `0.0` or below selects greedy decoding
+    pub temperature: f32,
+    /// Keep only the `top_k` most likely tokens, `0` disables
+    pub top_k: i32,
+    /// Nucleus sampling threshold, `1.0` disables
+    pub top_p: f32,
+    /// Minimum probability relative to the most likely token, `0.0` disables
+    pub min_p: f32,
+    /// Locally typical sampling threshold, `1.0` disables
+    pub typical_p: f32,
+    /// Penalty applied to tokens in the last `repeat_last_n` tokens, `1.0` disables
+    pub repeat_penalty: f32,
+    /// Penalty scaled by how often a token occurs in the window, `0.0` disables
+    pub frequency_penalty: f32,
+    /// Flat penalty for any token present in the window, `0.0` disables
+    pub presence_penalty: f32,
+    /// Number of most recent tokens considered by the penalties, `0` disables
+    pub repeat_last_n: usize,
+    /// Strings that end generation, they are not included in the output
+    pub stop: Vec<String>,
+    /// GBNF grammar the output must follow, see `json_schema_to_grammar`
+    pub grammar: Option<String>,
+    /// Return the log-probability of every generated token with this many alternatives
+    pub logprobs: Option<usize>,
+    /// Also return the log-probabilities of the prompt tokens
+    pub echo: bool,
+    /// LoRA adapters loaded with `LLM::load_lora_adapter` to apply, by name and scale
+    pub lora_adapters: Vec<(String, f32)>,
+    /// Stops generation early when triggered, the partial output is returned
+    pub cancel: Option<CancellationToken>,
+    /// What happens when the prompt and generated tokens no longer fit in `n_ctx`
+    pub context_overflow: ContextOverflow,
     pub embeddings: bool,
+    /// Pooling used by `LLM::embed`
+    pub pooling: Pooling,
+    /// L2-normalize the vectors returned by `LLM::embed`
+    pub normalize_embeddings: bool,
 }
 
 impl Default for InferenceParams {
     fn default() -> Self {
         Self {
             n_ctx: NonZeroU32::new(2048).unwrap(),
+            n_batch: 512,
+            n_ubatch: 512,
             n_threads: None,
             n_threads_batch: None,
             seed: 1234,
             max_tokens: 100,
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.05,
+            typical_p: 1.0,
+            repeat_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            repeat_last_n: 64,
+            stop: Vec::new(),
+            grammar: None,
+            logprobs: None,
+            echo: false,
+            lora_adapters: Vec::new(),
+            cancel: None,
+            context_overflow: ContextOverflow::Error,
             embeddings: false,
+            pooling: Pooling::Model,
+            normalize_embeddings: true,
         }
     }
 }
 
 // Internal conversion traits (private to crate)
-impl From<LoadParams> for llama_cpp_2::model::params::LlamaModelParams {
-    fn from(params: LoadParams) -> Self {
-        // Initialize default parameters and set existing fields
-        let mut model_params = llama_cpp_2::model::params::LlamaModelParams::default()
-            .with_n_gpu_layers(params.n_gpu_layers as u32)
-            .with_use_mlock(params.use_mlock)
-            .with_vocab_only(params.vocab_only);
-        model_params
-    }        
-}
-
 impl From<InferenceParams> for llama_cpp_2::context::params::LlamaContextParams {
     fn from(params: InferenceParams) -> Self {
-        let mut ctx_params = Self::default().with_n_ctx(Some(params.n_ctx)).with_seed(params.seed);
+        let mut ctx_params = Self::default()
+            .with_n_ctx(Some(params.n_ctx))
+            .with_n_batch(params.n_batch)
+            .with_n_ubatch(params.n_ubatch.min(params.n_batch))
+            .with_seed(params.seed);
         if let Some(n_threads) = params.n_threads {
             ctx_params = ctx_params.with_n_threads(n_threads);
         }
         if let Some(n_threads_batch) = params.n_threads_batch {
             ctx_params = ctx_params.with_n_threads_batch(n_threads_batch);
         }
-        ctx_params = ctx_params.with_embeddings(params.embeddings);
+        ctx_params = ctx_params
+            .with_embeddings(params.embeddings)
+            .with_pooling_type(params.pooling.into());
         ctx_params
     }
 }
+
 // Struct representing the Language Model
 pub struct LLM {
+    // Declared before `model` so the adapters are dropped first: llama.cpp
+    // frees adapters still attached to a model when the model is freed,
+    // which would free them twice
+    lora_adapters: Mutex<HashMap<String, lora::LoraAdapter>>,
     model: LlamaModel,
+    chat_template: Option<String>,
+    file_size: u64,
+    sha256: String,
 }
 
 impl LLM {
     /// Loads the model based on the provided ModelType
     pub fn load(model_type: ModelType, load_params: LoadParams) -> Result<Self> {
+        Self::load_with_progress(model_type, load_params, |_| true)
+    }
+
+    /// Loads the model, reporting progress between `0.0` and `1.0` to `progress`.
+    ///
+    /// The model file is read once to compute its SHA-256 digest, which also
+    /// warms the page cache, and then loaded by llama.cpp. Each pass covers
+    /// half of the progress range. Returning `false` from `progress` aborts
+    /// either pass with `LlmError::Aborted`, `1.0` is reported once the model
+    /// is loaded.
+    pub fn load_with_progress<F>(model_type: ModelType, load_params: LoadParams, mut progress: F) -> Result<Self>
+    where
+        F: FnMut(f32) -> bool,
+    {
         // Initialize backend only once
-        let backend = BACKEND.get_or_try_init(LlamaBackend::init)?;
+        BACKEND
+            .get_or_try_init(LlamaBackend::init)
+            .llama_context(|| "unable to initialize the llama.cpp backend".to_string())?;
 
         let model_path = match model_type {
-            ModelType::Local { path } => path
+            ModelType::Local { path } => path,
+            ModelType::HuggingFace { repo, file, revision } => hub::resolve(
+                &repo,
+                &file,
+                revision.as_deref(),
+                load_params.hf_cache_dir.clone(),
+                load_params.hf_offline,
+            )?,
         };
         
-        let model_params = LlamaModelParams::from(load_params);
-        let model = LlamaModel::load_from_file(backend, &model_path, &model_params)
-            .with_context(|| "unable to load model")?;
-            
+        // The file stays open so llama.cpp loads the same file that is hashed here
+        let mut file = std::fs::File::open(&model_path)
+            .map_err(|source| LlmError::read(&model_path, source))?;
+        let opened = file
+            .metadata()
+            .map_err(|source| LlmError::Read { path: model_path.clone(), source })?;
+        let file_size = opened.len();
+        let sha256 = load::read_ahead(&mut file, &model_path, file_size, &mut progress)?;
+        if let Some(expected) = &load_params.expected_sha256 {
+            if !expected.trim().eq_ignore_ascii_case(&sha256) {
+                return Err(LlmError::DigestMismatch {
+                    path: model_path,
+                    expected: expected.clone(),
+                    actual: sha256,
+                });
+            }
+        }
+
+        let model = load::load_model(&file, &opened, &model_path, &load_params, &mut |fraction| {
+            progress(load::HASH_PROGRESS_SHARE + (1.0 - load::HASH_PROGRESS_SHARE) * fraction)
+        })?;
+        progress(1.0);
+
         Ok(LLM {
+            lora_adapters: Mutex::new(HashMap::new()),
             model,
+            chat_template: load_params.chat_template,
+            file_size,
+            sha256,
         })
     }
 
-    /// Performs prediction based on the prompt and current parameters
-    pub fn predict<F>(&self, prompt: &str, inference_params: InferenceParams, mut callback: F) -> Result<String>
-    where
-        F: FnMut(&str),
-    {
-        let backend = BACKEND.get().expect("Backend not initialized");
-        let t_main_start = ggml_time_us();
-        let max_tokens = inference_params.max_tokens;
-        let ctx_params = LlamaContextParams::from(inference_params);
-        
-        let mut ctx = self
-            .model
-            .new_context(backend, ctx_params)
-            .context("unable to create the llama_context")?;
-
-        // Tokenize the prompt
-        let tokens_list = self
-            .model
-            .str_to_token(prompt, AddBos::Always)
-            .context("failed to tokenize prompt")?;
-
-        // Create batch for token processing
-        let mut batch = LlamaBatch::new(512, 1);
-        let last_index: i32 = (tokens_list.len() - 1) as i32;
-
-        for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-            batch.add(token, i, &[0], i == last_index)?;
-        }
-
-        ctx.decode(&mut batch)?;
-
-        // Generation loop
-        let mut n_cur = batch.n_tokens();
-        let mut output = String::new();
-        let mut decoder = UTF_8.new_decoder();
-
-        let mut n_decode = 0;
-        while n_cur <= max_tokens {
-            let candidates = ctx.candidates();
-            let candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
-            let new_token_id = ctx.sample_token_greedy(candidates_p);
-
-            // Check for end of generation
-            if self.model.is_eog_token(new_token_id) {
-                break;
-            }
-
-            // Decode token to string
-            let output_bytes = self.model.token_to_bytes(new_token_id, Special::Tokenize)?;
-            let mut token_string = String::with_capacity(32);
-            decoder.decode_to_string(&output_bytes, &mut token_string, false);
-            output.push_str(&token_string);
-
-            // Call the callback with the new token
-            callback(&token_string);
-            n_decode += 1;
-            // Process next token
-            batch.clear();
-            batch.add(new_token_id, n_cur, &[0], true)?;
-            ctx.decode(&mut batch)?;
-            n_cur += 1;
-        }
+    /// Lowercase hex SHA-256 digest of the loaded model file
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
 
-        let t_main_end = ggml_time_us();
+    /// Creates a fresh context for the loaded model
+    pub(crate) fn new_context(&self, inference_params: &InferenceParams) -> Result<LlamaContext<'_>> {
+        self.new_context_with_seqs(inference_params, 1)
+    }
 
-        let duration = Duration::from_micros((t_main_end - t_main_start) as u64);
+    /// Creates a fresh context that can hold `n_seq_max` sequences at once
+    pub(crate) fn new_context_with_seqs(&self, inference_params: &InferenceParams, n_seq_max: u32) -> Result<LlamaContext<'_>> {
+        let backend = BACKEND.get().expect("Backend not initialized");
+        let ctx_params = LlamaContextParams::from(inference_params.clone()).with_n_seq_max(n_seq_max);
+        let mut ctx = self.model.new_context(backend, ctx_params)?;
+        self.apply_lora_adapters(&mut ctx, &inference_params.lora_adapters)?;
+        Ok(ctx)
+    }
 
-        eprintln!(
-            "Time: {:.2}s, Speed: {:.2} t/s\n",
-            duration.as_secs_f32(),
-            n_decode as f32 / duration.as_secs_f32()
-        );
-    
-        callback("\n");
+    /// Creates a session that keeps its context and KV cache between calls
+    pub fn session(&self, inference_params: InferenceParams) -> Result<LlmSession<'_>> {
+        LlmSession::new(self, inference_params)
+    }
 
-        Ok(output)
+    /// Performs prediction based on the prompt and current parameters
+    pub fn predict<F>(&self, prompt: &str, inference_params: InferenceParams, callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        let mut session = self.session(inference_params)?;
+        session.predict(prompt, callback)
     }
 }
@@ -0,0 +1,248 @@
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::LlamaModelLoadError;
+use llama_cpp_sys_2::{llama_model, llama_model_kv_override, llama_model_kv_override__bindgen_ty_1};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::ffi::{c_char, c_void, CString};
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+
+use crate::{LlmError, LoadParams, Result};
+
+// Bytes read between two progress reports
+const READ_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+// Hashing and llama.cpp each read the whole file once, so each covers half of the reported progress
+pub(crate) const HASH_PROGRESS_SHARE: f32 = 0.5;
+// llama.cpp stores keys and string overrides in fixed buffers, including the terminating null byte
+const MAX_OVERRIDE_LEN: usize = 127;
+
+/// Typed value that replaces a GGUF metadata entry at load time
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvOverride {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+/// Copies `value` into a null-terminated llama.cpp buffer
+fn fixed_c_str(what: &str, value: &str) -> Result<[c_char; MAX_OVERRIDE_LEN + 1]> {
+    if value.len() > MAX_OVERRIDE_LEN || value.contains('\0') {
+        return Err(LlmError::InvalidRequest(format!(
+            "{} {:?} must be at most {} bytes without null bytes",
+            what, value, MAX_OVERRIDE_LEN
+        )));
+    }
+    let mut buffer: [c_char; MAX_OVERRIDE_LEN + 1] = [0; MAX_OVERRIDE_LEN + 1];
+    for (dst, src) in buffer.iter_mut().zip(value.bytes()) {
+        *dst = src as c_char;
+    }
+    Ok(buffer)
+}
+
+/// Converts the metadata overrides to the array llama.cpp expects, terminated by an entry with an empty key
+fn kv_overrides(overrides: &[(String, KvOverride)]) -> Result<Vec<llama_model_kv_override>> {
+    let mut entries = Vec::with_capacity(overrides.len() + 1);
+    for (key, value) in overrides {
+        // An empty key would end the array early
+        if key.is_empty() {
+            return Err(LlmError::invalid_request("override keys must not be empty"));
+        }
+        let (tag, value) = match value {
+            KvOverride::Int(value) => (
+                llama_cpp_sys_2::LLAMA_KV_OVERRIDE_TYPE_INT,
+                llama_model_kv_override__bindgen_ty_1 { val_i64: *value },
+            ),
+            KvOverride::Float(value) => (
+                llama_cpp_sys_2::LLAMA_KV_OVERRIDE_TYPE_FLOAT,
+                llama_model_kv_override__bindgen_ty_1 { val_f64: *value },
+            ),
+            KvOverride::Bool(value) => (
+                llama_cpp_sys_2::LLAMA_KV_OVERRIDE_TYPE_BOOL,
+                llama_model_kv_override__bindgen_ty_1 { val_bool: *value },
+            ),
+            KvOverride::Str(value) => (
+                llama_cpp_sys_2::LLAMA_KV_OVERRIDE_TYPE_STR,
+                llama_model_kv_override__bindgen_ty_1 {
+                    val_str: fixed_c_str("string override", value)?,
+                },
+            ),
+        };
+        entries.push(llama_model_kv_override {
+            tag,
+            key: fixed_c_str("override key", key)?,
+            __bindgen_anon_1: value,
+        });
+    }
+    // SAFETY: an all-zero entry is plain data with an empty key
+    entries.push(unsafe { std::mem::zeroed() });
+    Ok(entries)
+}
+
+/// State shared with `progress_callback` while llama.cpp loads a model
+struct LoadProgress<'p> {
+    progress: &'p mut dyn FnMut(f32) -> bool,
+    aborted: bool,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+unsafe extern "C" fn progress_callback(fraction: f32, user_data: *mut c_void) -> bool {
+    // SAFETY: `user_data` is the `LoadProgress` passed by `load_model`, which outlives the load
+    let state = unsafe { &mut *user_data.cast::<LoadProgress>() };
+    // A panic must not unwind into llama.cpp, it aborts the load and is resumed afterwards
+    match panic::catch_unwind(AssertUnwindSafe(|| (state.progress)(fraction))) {
+        Ok(true) => true,
+        Ok(false) => {
+            state.aborted = true;
+            false
+        }
+        Err(payload) => {
+            state.panic = Some(payload);
+            false
+        }
+    }
+}
+
+/// Loads a model with llama.cpp, passing its loading progress between `0.0`
+/// and `1.0` to `progress`.
+///
+/// `file` must be the opened model file at `path`, which is what gets
+/// loaded, see `open_file_path`, and `opened` its metadata from before it
+/// was hashed. Loading fails with `LlmError::Read` if the file has changed
+/// since, and with `expected_sha256` set the weights are read into memory
+/// instead of being mapped, so later writes to the file cannot alter them.
+/// llama-cpp-2 does not expose
+/// `progress_callback`, so the model is loaded through llama.cpp directly
+/// and wrapped in a `LlamaModel` afterwards.
+pub(crate) fn load_model(
+    file: &File,
+    opened: &Metadata,
+    path: &Path,
+    load_params: &LoadParams,
+    progress: &mut dyn FnMut(f32) -> bool,
+) -> Result<LlamaModel> {
+    let invalid = |source: LlamaModelLoadError| LlmError::InvalidModel {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    };
+    let open_path = open_file_path(file, path);
+    let c_path = open_path
+        .to_str()
+        .ok_or_else(|| invalid(LlamaModelLoadError::PathToStrError(path.to_path_buf())))?;
+    let c_path = CString::new(c_path).map_err(|error| invalid(LlamaModelLoadError::NullError(error)))?;
+    let overrides = kv_overrides(&load_params.kv_overrides)?;
+    let mut state = LoadProgress {
+        progress,
+        aborted: false,
+        panic: None,
+    };
+
+    // SAFETY: plain data returned by value
+    let mut params = unsafe { llama_cpp_sys_2::llama_model_default_params() };
+    params.n_gpu_layers = load_params.n_gpu_layers.try_into().unwrap_or(i32::MAX);
+    // A mapped file is paged in lazily, so the weights would follow writes made after hashing
+    params.use_mmap = load_params.use_mmap && load_params.expected_sha256.is_none();
+    params.use_mlock = load_params.use_mlock;
+    params.vocab_only = load_params.vocab_only;
+    params.kv_overrides = overrides.as_ptr();
+    params.progress_callback = Some(progress_callback);
+    params.progress_callback_user_data = ptr::addr_of_mut!(state).cast();
+
+    // SAFETY: the path, the overrides and the progress state outlive the call, which does not keep them
+    let model = unsafe { llama_cpp_sys_2::llama_load_model_from_file(c_path.as_ptr(), params) };
+    if let Some(payload) = state.panic {
+        if !model.is_null() {
+            // SAFETY: the model was just loaded and is not referenced anywhere else
+            unsafe { llama_cpp_sys_2::llama_free_model(model) };
+        }
+        panic::resume_unwind(payload);
+    }
+    let model = match NonNull::new(model) {
+        Some(model) => wrap_model(model),
+        None if state.aborted => return Err(LlmError::Aborted),
+        None => return Err(invalid(LlamaModelLoadError::NullResult)),
+    };
+    check_unchanged(file, opened, path, open_path == path)?;
+    Ok(model)
+}
+
+/// Takes ownership of a model loaded by llama.cpp, freeing it when dropped
+fn wrap_model(model: NonNull<llama_model>) -> LlamaModel {
+    // SAFETY: `LlamaModel` is `#[repr(transparent)]` over its `NonNull<llama_model>`
+    unsafe { std::mem::transmute::<NonNull<llama_model>, LlamaModel>(model) }
+}
+
+/// Reads the whole model file from the start, reporting the fraction read
+/// so far to `progress`, and returns its SHA-256 digest as lowercase hex.
+///
+/// This fills the page cache so llama.cpp can load the file without waiting
+/// on the disk, and gives the caller a chance to abort between chunks.
+pub(crate) fn read_ahead(file: &mut File, path: &Path, file_size: u64, progress: &mut dyn FnMut(f32) -> bool) -> Result<String> {
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    let mut n_read = 0u64;
+    let mut hasher = Sha256::new();
+    loop {
+        if !progress(HASH_PROGRESS_SHARE * n_read as f32 / file_size.max(1) as f32) {
+            return Err(LlmError::Aborted);
+        }
+        let n = file.read(&mut buffer).map_err(|source| LlmError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if n == 0 {
+            return Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect());
+        }
+        hasher.update(&buffer[..n]);
+        n_read += n as u64;
+    }
+}
+
+/// Path for llama.cpp to open the same file as `file`.
+///
+/// On Linux this is the descriptor's entry in `/proc`, so the file that was
+/// hashed is the one loaded even if `path` is replaced in between.
+fn open_file_path(file: &File, path: &Path) -> PathBuf {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        let fd_path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+        if fd_path.exists() {
+            return fd_path;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+    path.to_path_buf()
+}
+
+/// Checks that `file` was not written to since `opened` was taken, going by
+/// its size and modification time, and, when llama.cpp had to load it by
+/// name, that `path` still names it
+fn check_unchanged(file: &File, opened: &Metadata, path: &Path, by_name: bool) -> Result<()> {
+    let read_error = |source| LlmError::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+    let current = file.metadata().map_err(read_error)?;
+    if current.len() != opened.len() || current.modified().ok() != opened.modified().ok() {
+        return Err(read_error(io::Error::other("the file was modified after its digest was computed")));
+    }
+    if !by_name {
+        return Ok(());
+    }
+    let loaded = std::fs::metadata(path).map_err(read_error)?;
+    let mut same = loaded.len() == opened.len() && loaded.modified().ok() == opened.modified().ok();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        same &= loaded.dev() == opened.dev() && loaded.ino() == opened.ino();
+    }
+    if same {
+        Ok(())
+    } else {
+        Err(read_error(io::Error::other("the file was replaced after its digest was computed")))
+    }
+}
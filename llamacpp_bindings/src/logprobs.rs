@@ -0,0 +1,105 @@
+use llama_cpp_2::model::Special;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::{Result, LLM};
+
+/// A candidate token together with its log-probability
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// Log-probability of a token that was generated or evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub text: String,
+    /// Natural log of the probability the model assigned to `token`, before any sampling filters
+    pub logprob: f32,
+    /// The most likely tokens at this position, most likely first
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+impl LLM {
+    /// Builds the log-probability entry of `token` from the raw logits of the position before it
+    pub(crate) fn token_logprob(&self, logits: &[f32], token: LlamaToken, top_n: usize) -> Result<TokenLogprob> {
+        let log_norm = log_sum_exp(logits);
+
+        let mut top_logprobs = Vec::with_capacity(top_n);
+        for id in top_indices(logits, top_n) {
+            let candidate = LlamaToken::new(id as i32);
+            top_logprobs.push(TopLogprob {
+                token: id as u32,
+                text: self.token_text(candidate)?,
+                logprob: logits[id] - log_norm,
+            });
+        }
+
+        Ok(TokenLogprob {
+            token: token.0 as u32,
+            text: self.token_text(token)?,
+            logprob: logits[token.0 as usize] - log_norm,
+            top_logprobs,
+        })
+    }
+
+    /// Text of a single token, bytes that are not valid UTF-8 on their own are replaced
+    fn token_text(&self, token: LlamaToken) -> Result<String> {
+        let bytes = self.model.token_to_bytes(token, Special::Tokenize)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Numerically stable `ln(sum(exp(x)))`
+pub(crate) fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    max + logits.iter().map(|logit| (logit - max).exp()).sum::<f32>().ln()
+}
+
+/// Indices of the `n` largest logits, largest first
+fn top_indices(logits: &[f32], n: usize) -> Vec<usize> {
+    let n = n.min(logits.len());
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut indices: Vec<usize> = (0..logits.len()).collect();
+    indices.select_nth_unstable_by(n - 1, |a, b| logits[*b].total_cmp(&logits[*a]));
+    indices.truncate(n);
+    indices.sort_unstable_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_sum_exp, top_indices};
+
+    #[test]
+    fn top_indices_are_sorted_largest_first() {
+        let logits = [0.5, 3.0, -1.0, 2.0, 3.5];
+        assert_eq!(top_indices(&logits, 3), vec![4, 1, 3]);
+    }
+
+    #[test]
+    fn top_indices_are_limited_to_the_vocabulary() {
+        let logits = [1.0, 2.0];
+        assert_eq!(top_indices(&logits, 5), vec![1, 0]);
+        assert!(top_indices(&logits, 0).is_empty());
+        assert!(top_indices(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn log_sum_exp_is_stable_for_large_logits() {
+        let logits = [1000.0, 1000.0];
+        assert!((log_sum_exp(&logits) - (1000.0 + 2f32.ln())).abs() < 1e-3);
+    }
+
+    #[test]
+    fn log_probabilities_sum_to_one() {
+        let logits = [0.1, -2.0, 3.0, 0.7];
+        let log_norm = log_sum_exp(&logits);
+        let total: f32 = logits.iter().map(|logit| (logit - log_norm).exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+    }
+}
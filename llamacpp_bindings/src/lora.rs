@@ -0,0 +1,67 @@
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::model::LlamaLoraAdapter;
+use std::path::Path;
+
+use crate::error::LlamaResultExt;
+use crate::{LlmError, Result, LLM};
+
+/// A LoRA adapter owned by an `LLM`.
+pub(crate) struct LoraAdapter(LlamaLoraAdapter);
+
+// SAFETY: llama.cpp only reads an adapter's tensors after it has been
+// loaded, and the adapters are only handed to llama.cpp while the `LLM`
+// holds the lock around its adapter map.
+unsafe impl Send for LoraAdapter {}
+
+impl LLM {
+    /// Loads a GGUF LoRA adapter for this model and registers it under `name`.
+    ///
+    /// The adapter shares the base model weights and is only applied to
+    /// contexts that select it through `InferenceParams::lora_adapters`.
+    pub fn load_lora_adapter(&self, name: &str, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let already_loaded = || LlmError::InvalidRequest(format!("a LoRA adapter named {} is already loaded", name));
+        if self.lora_adapters.lock().unwrap().contains_key(name) {
+            return Err(already_loaded());
+        }
+        // llama.cpp does not tell a missing file from an invalid one
+        std::fs::metadata(path).map_err(|source| LlmError::read(path, source))?;
+        // Loaded without holding the lock, which new contexts need to apply adapters
+        let adapter = self
+            .model
+            .lora_adapter_init(path)
+            .map_err(|source| LlmError::InvalidModel { path: path.to_path_buf(), source: Box::new(source) })?;
+
+        let mut adapters = self.lora_adapters.lock().unwrap();
+        // Another request may have loaded the same name in the meantime
+        if adapters.contains_key(name) {
+            return Err(already_loaded());
+        }
+        adapters.insert(name.to_string(), LoraAdapter(adapter));
+        Ok(())
+    }
+
+    /// Names of the LoRA adapters loaded for this model
+    pub fn lora_adapter_names(&self) -> Vec<String> {
+        let adapters = self.lora_adapters.lock().unwrap();
+        let mut names: Vec<String> = adapters.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Attaches the selected adapters, each with its scale, to `ctx`
+    pub(crate) fn apply_lora_adapters(&self, ctx: &mut LlamaContext, selection: &[(String, f32)]) -> Result<()> {
+        if selection.is_empty() {
+            return Ok(());
+        }
+        let mut adapters = self.lora_adapters.lock().unwrap();
+        for (name, scale) in selection {
+            let adapter = adapters
+                .get_mut(name)
+                .ok_or_else(|| LlmError::InvalidRequest(format!("LoRA adapter {} is not loaded", name)))?;
+            ctx.lora_adapter_set(&mut adapter.0, *scale)
+                .llama_context(|| format!("unable to apply LoRA adapter {}", name))?;
+        }
+        Ok(())
+    }
+}
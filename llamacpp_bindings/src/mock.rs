@@ -0,0 +1,316 @@
+use std::time::Duration;
+
+use crate::completion::CancellationToken;
+use crate::session::Generation;
+use crate::state::fnv1a;
+use crate::{
+    ChatMessage, Completion, FinishReason, InferenceParams, LlmError, LoadParams, ModelType, Result, TextGenerator,
+    TokenLogprob,
+};
+
+const MOCK_N_VOCAB: u32 = 32000;
+const MOCK_BOS_TOKEN: u32 = 1;
+// Ids below this are reserved for special tokens
+const MOCK_FIRST_TEXT_TOKEN: u32 = 3;
+const MOCK_N_EMBD: usize = 64;
+
+/// Deterministic `TextGenerator` that needs no model file.
+///
+/// Text is split into one token per word, each word keeping the whitespace
+/// before it, and token ids are derived from a hash of the word. An echo
+/// backend generates the tokens of the prompt, a scripted one the pieces it
+/// was created with, both honouring `max_tokens`, stop strings and
+/// cancellation like `LLM`. Embeddings are pseudo-random vectors seeded by
+/// the input text, so equal inputs always get equal vectors.
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    script: Option<Vec<String>>,
+}
+
+impl MockBackend {
+    /// Backend that generates its prompt back
+    pub fn echo() -> Self {
+        Self::default()
+    }
+
+    /// Backend that generates `pieces` in order, one token each, whatever the prompt
+    pub fn scripted<S: Into<String>>(pieces: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            script: Some(pieces.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+impl TextGenerator for MockBackend {
+    /// Returns an echo backend without touching `model_type`
+    fn load_with_progress(
+        _model_type: ModelType,
+        _load_params: LoadParams,
+        progress: &mut dyn FnMut(f32) -> bool,
+    ) -> Result<Self> {
+        if !progress(0.0) || !progress(1.0) {
+            return Err(LlmError::Aborted);
+        }
+        Ok(Self::echo())
+    }
+
+    fn predict(&self, prompt: &str, inference_params: InferenceParams, callback: &mut dyn FnMut(&str)) -> Result<Completion> {
+        let prompt_pieces = split_words(prompt);
+        if prompt_pieces.is_empty() {
+            return Err(LlmError::invalid_request("prompt is empty"));
+        }
+        // The BOS token counts towards the prompt like with llama.cpp
+        let n_prompt_tokens = prompt_pieces.len() + 1;
+        if n_prompt_tokens > inference_params.n_ctx.get() as usize {
+            return Err(LlmError::ContextOverflow {
+                n_tokens: n_prompt_tokens,
+                n_ctx: inference_params.n_ctx.get(),
+            });
+        }
+
+        let pieces = match &self.script {
+            Some(script) => script.iter().map(String::as_str).collect(),
+            None => prompt_pieces,
+        };
+        let mut generation = Generation::new(inference_params.max_tokens, &inference_params.stop);
+        generation.record_prompt(n_prompt_tokens, Duration::ZERO);
+
+        for piece in pieces {
+            let cancelled = inference_params
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled);
+            if generation.reached_limit(cancelled) {
+                break;
+            }
+            // Output is fully determined, so every token has probability one
+            let logprob = inference_params.logprobs.map(|_| TokenLogprob {
+                token: token_id(piece),
+                text: piece.to_string(),
+                logprob: 0.0,
+                top_logprobs: Vec::new(),
+            });
+            let released = generation.push_piece(piece, logprob);
+            if !released.is_empty() {
+                callback(&released);
+            }
+        }
+        // Running out of pieces below `max_tokens` is the mock's end-of-generation token
+        generation.reached_limit(false);
+        generation.finish(FinishReason::Stop);
+
+        let (completion, remaining) = generation.into_completion();
+        if !remaining.is_empty() {
+            callback(&remaining);
+        }
+        Ok(completion)
+    }
+
+    fn tokenize(&self, text: &str, add_bos: bool, _parse_special: bool) -> Result<Vec<u32>> {
+        let bos = add_bos.then_some(MOCK_BOS_TOKEN);
+        Ok(bos.into_iter().chain(split_words(text).into_iter().map(token_id)).collect())
+    }
+
+    fn embed(&self, inputs: &[&str], inference_params: InferenceParams) -> Result<Vec<Vec<f32>>> {
+        if inputs.iter().any(|input| input.is_empty()) {
+            return Err(LlmError::invalid_request("inputs must not be empty"));
+        }
+        Ok(inputs
+            .iter()
+            .map(|input| {
+                let mut state = fnv1a(input.as_bytes());
+                let mut embedding: Vec<f32> = (0..MOCK_N_EMBD)
+                    .map(|_| {
+                        state = xorshift(state);
+                        (state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+                    })
+                    .collect();
+                if inference_params.normalize_embeddings {
+                    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    if norm > 0.0 {
+                        embedding.iter_mut().for_each(|x| *x /= norm);
+                    }
+                }
+                embedding
+            })
+            .collect())
+    }
+
+    fn chat_template(&self) -> Option<String> {
+        Some("chatml".to_string())
+    }
+
+    /// Renders `messages` in the ChatML format
+    fn apply_chat_template(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let mut prompt = String::new();
+        for message in messages {
+            prompt.push_str(&format!("<|im_start|>{}\n{}<|im_end|>\n", message.role, message.content));
+        }
+        if add_generation_prompt {
+            prompt.push_str("<|im_start|>assistant\n");
+        }
+        Ok(prompt)
+    }
+}
+
+/// Splits `text` into words that keep the whitespace in front of them
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut in_word = false;
+    for (index, c) in text.char_indices() {
+        if c.is_whitespace() && in_word {
+            words.push(&text[start..index]);
+            start = index;
+            in_word = false;
+        } else if !c.is_whitespace() {
+            in_word = true;
+        }
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+fn token_id(piece: &str) -> u32 {
+    MOCK_FIRST_TEXT_TOKEN + (fnv1a(piece.as_bytes()) % (MOCK_N_VOCAB - MOCK_FIRST_TEXT_TOKEN) as u64) as u32
+}
+
+fn xorshift(mut state: u64) -> u64 {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    fn params(max_tokens: i32, stop: &[&str]) -> InferenceParams {
+        InferenceParams {
+            max_tokens,
+            stop: stop.iter().map(|stop| stop.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn predict(backend: &MockBackend, prompt: &str, inference_params: InferenceParams) -> (Completion, String) {
+        let mut streamed = String::new();
+        let completion = backend
+            .predict(prompt, inference_params, &mut |piece| streamed.push_str(piece))
+            .unwrap();
+        (completion, streamed)
+    }
+
+    #[test]
+    fn split_words_keeps_leading_whitespace() {
+        assert!(split_words("").is_empty());
+        assert_eq!(split_words("hello"), ["hello"]);
+        assert_eq!(split_words(" Hello  world\n"), [" Hello", "  world", "\n"]);
+        assert_eq!(split_words("héllo wörld"), ["héllo", " wörld"]);
+    }
+
+    #[test]
+    fn split_words_covers_the_whole_text() {
+        let text = "\tone  two\nthree ";
+        assert_eq!(split_words(text).concat(), text);
+    }
+
+    #[test]
+    fn tokenize_is_deterministic() {
+        let backend = MockBackend::echo();
+        let tokens = backend.tokenize("the cat the", true, false).unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[0], MOCK_BOS_TOKEN);
+        assert_ne!(tokens[1], tokens[3]);
+        assert_eq!(tokens[2], backend.tokenize(" cat", false, false).unwrap()[0]);
+        assert!(tokens[1..].iter().all(|&token| (MOCK_FIRST_TEXT_TOKEN..MOCK_N_VOCAB).contains(&token)));
+    }
+
+    #[test]
+    fn echo_generates_the_prompt() {
+        let (completion, streamed) = predict(&MockBackend::echo(), "one two three", params(100, &[]));
+        assert_eq!(completion.text, "one two three");
+        assert_eq!(streamed, completion.text);
+        assert_eq!(completion.finish_reason, FinishReason::Stop);
+        assert_eq!(completion.stats.n_prompt_tokens, 4);
+        assert_eq!(completion.stats.n_generated_tokens, 3);
+    }
+
+    #[test]
+    fn honours_max_tokens() {
+        let (completion, streamed) = predict(&MockBackend::echo(), "one two three", params(2, &[]));
+        assert_eq!(completion.text, "one two");
+        assert_eq!(streamed, completion.text);
+        assert_eq!(completion.finish_reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn honours_stop_strings_across_pieces() {
+        let backend = MockBackend::scripted(["The", " ans", "wer", " is", " 42"]);
+        let (completion, streamed) = predict(&backend, "question", params(100, &["swer is"]));
+        assert_eq!(completion.text, "The an");
+        assert_eq!(streamed, completion.text);
+        assert_eq!(completion.finish_reason, FinishReason::Stop);
+    }
+
+    #[test]
+    fn honours_cancellation() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let inference_params = InferenceParams {
+            cancel: Some(cancel),
+            ..params(100, &[])
+        };
+        let (completion, streamed) = predict(&MockBackend::echo(), "one two three", inference_params);
+        assert!(completion.text.is_empty());
+        assert!(streamed.is_empty());
+        assert_eq!(completion.finish_reason, FinishReason::Cancelled);
+    }
+
+    #[test]
+    fn rejects_prompts_longer_than_the_context() {
+        let inference_params = InferenceParams {
+            n_ctx: NonZeroU32::new(2).unwrap(),
+            ..Default::default()
+        };
+        let result = MockBackend::echo().predict("one two", inference_params, &mut |_| {});
+        assert!(matches!(result, Err(LlmError::ContextOverflow { n_tokens: 3, n_ctx: 2 })));
+    }
+
+    #[test]
+    fn embeddings_depend_only_on_the_input() {
+        let backend = MockBackend::echo();
+        let inference_params = InferenceParams {
+            normalize_embeddings: true,
+            ..Default::default()
+        };
+        let embeddings = backend.embed(&["a", "b", "a"], inference_params).unwrap();
+        assert_eq!(embeddings[0], embeddings[2]);
+        assert_ne!(embeddings[0], embeddings[1]);
+        for embedding in &embeddings {
+            assert_eq!(embedding.len(), MOCK_N_EMBD);
+            let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn load_can_be_aborted() {
+        let path = ModelType::Local {
+            path: "unused.gguf".into(),
+        };
+        let result = MockBackend::load_with_progress(path.clone(), LoadParams::default(), &mut |_| false);
+        assert!(matches!(result, Err(LlmError::Aborted)));
+        let mut reported = Vec::new();
+        MockBackend::load_with_progress(path, LoadParams::default(), &mut |progress| {
+            reported.push(progress);
+            true
+        })
+        .unwrap();
+        assert_eq!(reported, [0.0, 1.0]);
+    }
+}
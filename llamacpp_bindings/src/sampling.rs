@@ -0,0 +1,116 @@
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::grammar::LlamaGrammar;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::LlamaToken;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::{InferenceParams, LlmError, Result};
+
+/// Sampler chain built from `InferenceParams`.
+///
+/// The steps run in the same order as llama.cpp's default chain: repetition
+/// penalties, the grammar constraint, top-k, typical-p, top-p, min-p,
+/// temperature and finally a draw from the remaining distribution using the
+/// context RNG, which is seeded from `InferenceParams::seed`. A temperature
+/// of `0.0` or below falls back to greedy sampling over the tokens the
+/// grammar allows.
+pub(crate) struct Sampler {
+    temperature: f32,
+    top_k: i32,
+    top_p: f32,
+    min_p: f32,
+    typical_p: f32,
+    repeat_penalty: f32,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    repeat_last_n: usize,
+    // Most recent tokens, used as the penalty window
+    history: VecDeque<LlamaToken>,
+    grammar: Option<LlamaGrammar>,
+}
+
+impl Sampler {
+    pub(crate) fn new(params: &InferenceParams) -> Result<Self> {
+        let grammar = params
+            .grammar
+            .as_deref()
+            .map(LlamaGrammar::from_str)
+            .transpose()
+            .map_err(|e| LlmError::InvalidRequest(format!("failed to parse grammar: {}", e)))?;
+        Ok(Self {
+            temperature: params.temperature,
+            top_k: params.top_k,
+            top_p: params.top_p,
+            min_p: params.min_p,
+            typical_p: params.typical_p,
+            repeat_penalty: params.repeat_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
+            repeat_last_n: params.repeat_last_n,
+            history: VecDeque::with_capacity(params.repeat_last_n),
+            grammar,
+        })
+    }
+
+    fn penalties_enabled(&self) -> bool {
+        self.repeat_last_n > 0
+            && (self.repeat_penalty != 1.0 || self.frequency_penalty != 0.0 || self.presence_penalty != 0.0)
+    }
+
+    /// Picks the next token from the candidate distribution
+    pub(crate) fn sample(&mut self, ctx: &mut LlamaContext, mut candidates: LlamaTokenDataArray) -> LlamaToken {
+        if self.penalties_enabled() && !self.history.is_empty() {
+            let last_tokens: Vec<LlamaToken> = self.history.iter().copied().collect();
+            candidates.sample_repetition_penalty(
+                None,
+                &last_tokens,
+                last_tokens.len(),
+                self.repeat_penalty,
+                self.frequency_penalty,
+                self.presence_penalty,
+            );
+        }
+
+        // Tokens the grammar does not allow at this point are masked out
+        if let Some(grammar) = &self.grammar {
+            ctx.sample_grammar(&mut candidates, grammar);
+        }
+
+        if self.temperature <= 0.0 {
+            return ctx.sample_token_greedy(candidates);
+        }
+
+        let min_keep = 1;
+        if self.top_k > 0 {
+            candidates.sample_top_k(None, self.top_k, min_keep);
+        }
+        if self.typical_p < 1.0 {
+            candidates.sample_typical(None, self.typical_p, min_keep);
+        }
+        if self.top_p < 1.0 {
+            candidates.sample_top_p(None, self.top_p, min_keep);
+        }
+        if self.min_p > 0.0 {
+            candidates.sample_min_p(None, self.min_p, min_keep);
+        }
+        candidates.sample_temp(None, self.temperature);
+
+        candidates.sample_token(ctx)
+    }
+
+    /// Records a token that was emitted so it counts towards the penalties
+    /// and advances the grammar
+    pub(crate) fn accept(&mut self, ctx: &mut LlamaContext, token: LlamaToken) {
+        if let Some(grammar) = &mut self.grammar {
+            ctx.grammar_accept_token(grammar, token);
+        }
+        if self.repeat_last_n == 0 {
+            return;
+        }
+        if self.history.len() == self.repeat_last_n {
+            self.history.pop_front();
+        }
+        self.history.push_back(token);
+    }
+}
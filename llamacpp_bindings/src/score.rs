@@ -0,0 +1,162 @@
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::logprobs::log_sum_exp;
+use crate::{InferenceParams, LlmError, Result, TokenLogprob, LLM};
+
+/// Log-likelihood of a continuation, returned by `LLM::score`
+#[derive(Debug, Clone, Default)]
+pub struct Score {
+    /// Sum of the log-probabilities of the continuation tokens
+    pub logprob: f32,
+    /// One entry per continuation token, without alternatives
+    pub tokens: Vec<TokenLogprob>,
+    /// Whether every continuation token was the model's most likely choice
+    pub is_greedy: bool,
+}
+
+/// Result of `LLM::perplexity`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Perplexity {
+    /// `exp` of the mean negative log-likelihood per token
+    pub perplexity: f64,
+    /// Number of tokens that were scored
+    pub n_tokens: usize,
+    /// Number of windows the text was evaluated in
+    pub n_windows: usize,
+}
+
+impl LLM {
+    /// Computes how likely the model finds `continuation` after `context`,
+    /// without sampling anything.
+    ///
+    /// Both texts are tokenized separately, so the split between them stays
+    /// on a token boundary. As with most tokenizers a word is a different
+    /// token with and without its leading space, a continuation usually
+    /// starts with one. Special tokens such as `<|im_start|>` are parsed like
+    /// in `predict`, so a chat-templated context is scored as it would be
+    /// served. Comparing the scores of several continuations of the same
+    /// context ranks the answers of a multiple-choice question.
+    pub fn score(&self, context: &str, continuation: &str, inference_params: InferenceParams) -> Result<Score> {
+        let mut tokens = self.str_to_tokens(context, true, true)?;
+        let continuation_tokens = self.str_to_tokens(continuation, false, true)?;
+        if continuation_tokens.is_empty() {
+            return Err(LlmError::invalid_request("continuation is empty"));
+        }
+        let n_scored = continuation_tokens.len();
+        tokens.extend(continuation_tokens);
+
+        let n_ctx = inference_params.n_ctx.get();
+        if tokens.len() > n_ctx as usize {
+            return Err(LlmError::ContextOverflow {
+                n_tokens: tokens.len(),
+                n_ctx,
+            });
+        }
+
+        let mut ctx = self.new_context(&inference_params)?;
+        let mut score = Score {
+            is_greedy: true,
+            ..Default::default()
+        };
+        score_tokens(&mut ctx, &tokens, n_scored, inference_params.n_batch, |logits, token| {
+            let logprob = self.token_logprob(logits, token, 0)?;
+            score.logprob += logprob.logprob;
+            score.is_greedy &= is_most_likely(logits, token);
+            score.tokens.push(logprob);
+            Ok(())
+        })?;
+        Ok(score)
+    }
+
+    /// Computes the perplexity of the model on `text`.
+    ///
+    /// Texts longer than `n_ctx` are evaluated in windows of `n_ctx` tokens
+    /// that start `stride` tokens apart, each scoring only the tokens not
+    /// scored by the previous one. A smaller stride gives every token more
+    /// context, and a more accurate result, at the cost of evaluating more
+    /// windows. Every window starts with the BOS token. Unlike prompts, the
+    /// text is treated as plain text, special token spellings in it are not
+    /// parsed, as in llama.cpp's perplexity tool.
+    pub fn perplexity(&self, text: &str, stride: usize, inference_params: InferenceParams) -> Result<Perplexity> {
+        let tokens = self.str_to_tokens(text, false, false)?;
+        if tokens.is_empty() {
+            return Err(LlmError::invalid_request("text is empty"));
+        }
+        // One position of each window is taken by BOS
+        let n_window = inference_params.n_ctx.get() as usize - 1;
+        if stride == 0 || stride > n_window {
+            return Err(LlmError::InvalidRequest(format!(
+                "stride must be between 1 and {}, got {}",
+                n_window, stride
+            )));
+        }
+
+        let mut ctx = self.new_context(&inference_params)?;
+        let mut nll = 0.0f64;
+        let mut result = Perplexity::default();
+        let mut window = Vec::with_capacity(n_window + 1);
+        let mut begin = 0;
+        let mut scored_end = 0;
+        loop {
+            let end = (begin + n_window).min(tokens.len());
+            window.clear();
+            window.push(self.model.token_bos());
+            window.extend_from_slice(&tokens[begin..end]);
+
+            ctx.clear_kv_cache();
+            score_tokens(&mut ctx, &window, end - scored_end, inference_params.n_batch, |logits, token| {
+                nll -= (logits[token.0 as usize] - log_sum_exp(logits)) as f64;
+                Ok(())
+            })?;
+            result.n_tokens += end - scored_end;
+            result.n_windows += 1;
+
+            scored_end = end;
+            if end == tokens.len() {
+                break;
+            }
+            begin += stride;
+        }
+
+        result.perplexity = (nll / result.n_tokens as f64).exp();
+        Ok(result)
+    }
+}
+
+/// Decodes `tokens` from the start of an empty context and passes the
+/// logits that predict each of the last `n_scored` tokens to `score`,
+/// together with that token
+fn score_tokens<F>(ctx: &mut LlamaContext, tokens: &[LlamaToken], n_scored: usize, n_batch: u32, mut score: F) -> Result<()>
+where
+    F: FnMut(&[f32], LlamaToken) -> Result<()>,
+{
+    // The first token has nothing before it to be predicted from
+    let first_logits = tokens.len() - n_scored.min(tokens.len() - 1) - 1;
+    let n_batch = n_batch.max(1) as usize;
+    let mut batch = LlamaBatch::new(n_batch.min(tokens.len()), 1);
+    for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+        let offset = chunk_index * n_batch;
+        batch.clear();
+        for (i, token) in chunk.iter().enumerate() {
+            let position = offset + i;
+            let logits = position >= first_logits && position + 1 < tokens.len();
+            batch.add(*token, position as i32, &[0], logits)?;
+        }
+        ctx.decode(&mut batch)?;
+
+        for i in 0..chunk.len() {
+            let position = offset + i;
+            if position >= first_logits && position + 1 < tokens.len() {
+                score(ctx.get_logits_ith(i as i32), tokens[position + 1])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_most_likely(logits: &[f32], token: LlamaToken) -> bool {
+    let logit = logits[token.0 as usize];
+    logits.iter().all(|&other| other <= logit)
+}
@@ -0,0 +1,540 @@
+use encoding_rs::{Decoder, UTF_8};
+use std::time::{Duration, Instant};
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::Special;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::completion::{CancellationToken, GenerationStats};
+use crate::logprobs::TokenLogprob;
+use crate::sampling::Sampler;
+use crate::stop::StopMatcher;
+use crate::error::LlamaResultExt;
+use crate::{Completion, FinishReason, InferenceParams, LlmError, Result, LLM};
+
+/// Policy applied when a session runs out of context space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextOverflow {
+    /// Fail with an error
+    #[default]
+    Error,
+    /// End the generation with `FinishReason::Length`, a prompt that does not fit is still an error
+    Stop,
+    /// Discard the oldest tokens after the first `n_keep` and continue.
+    ///
+    /// Half of the unpinned tokens are dropped at a time, the pinned prefix
+    /// is typically the system prompt.
+    Shift { n_keep: usize },
+}
+
+/// A context that stays alive across calls, together with the tokens that
+/// are currently evaluated in its KV cache.
+///
+/// Feeding a prompt that starts with tokens the session has already seen only
+/// decodes the new suffix, so multi-turn conversations do not pay the full
+/// prefill cost on every turn.
+pub struct LlmSession<'a> {
+    pub(crate) llm: &'a LLM,
+    pub(crate) ctx: LlamaContext<'a>,
+    pub(crate) params: InferenceParams,
+    pub(crate) sampler: Sampler,
+    // Tokens held in the KV cache, in position order
+    pub(crate) tokens: Vec<LlamaToken>,
+    // Batch index of the logits belonging to the last token in `tokens`,
+    // `None` when that token was decoded without logits
+    logits_idx: Option<i32>,
+}
+
+impl<'a> LlmSession<'a> {
+    pub(crate) fn new(llm: &'a LLM, params: InferenceParams) -> Result<Self> {
+        let ctx = llm.new_context(&params)?;
+        Ok(Self {
+            llm,
+            ctx,
+            sampler: Sampler::new(&params)?,
+            params,
+            tokens: Vec::new(),
+            logits_idx: None,
+        })
+    }
+
+    /// Number of tokens currently held in the KV cache
+    pub fn n_tokens(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Clears the KV cache and the sampler state, including any grammar progress
+    pub fn reset(&mut self) -> Result<()> {
+        self.ctx.clear_kv_cache();
+        self.tokens.clear();
+        self.logits_idx = None;
+        self.sampler = Sampler::new(&self.params)?;
+        Ok(())
+    }
+
+    /// Replaces the cancellation token checked by later calls.
+    ///
+    /// A token stays cancelled once triggered, so a session whose generation
+    /// was cancelled needs a new token, or `None`, before it can generate again.
+    pub fn set_cancel(&mut self, cancel: Option<CancellationToken>) {
+        self.params.cancel = cancel;
+    }
+
+    /// Evaluates `text` after whatever is already in the session.
+    ///
+    /// Fails with `LlmError::Cancelled` when the cancellation token fires
+    /// between two chunks of `text`, leaving the session as it was before.
+    pub fn append(&mut self, text: &str) -> Result<()> {
+        let tokens = self.llm.str_to_tokens(text, self.tokens.is_empty(), true)?;
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        self.eval(&tokens)
+    }
+
+    /// Makes `prompt` the full content of the session.
+    ///
+    /// The longest prefix shared with the tokens already in the KV cache is
+    /// kept and only the remaining tokens are decoded. When
+    /// `InferenceParams::echo` is set the whole prompt is evaluated instead
+    /// and the log-probabilities of its tokens are returned. Fails with
+    /// `LlmError::Cancelled` when the cancellation token fires between two
+    /// chunks of the prompt, the session then only keeps the shared prefix.
+    pub fn set_prompt(&mut self, prompt: &str) -> Result<Vec<TokenLogprob>> {
+        self.eval_prompt(prompt).map(|(_, logprobs)| logprobs)
+    }
+
+    /// Same as `set_prompt`, also returning the number of prompt tokens
+    pub(crate) fn eval_prompt(&mut self, prompt: &str) -> Result<(usize, Vec<TokenLogprob>)> {
+        let tokens = self.llm.str_to_tokens(prompt, true, true)?;
+        self.eval_prompt_tokens(tokens)
+    }
+
+    /// Same as `eval_prompt` for a prompt that is already tokenized
+    pub(crate) fn eval_prompt_tokens(&mut self, tokens: Vec<LlamaToken>) -> Result<(usize, Vec<TokenLogprob>)> {
+        if tokens.is_empty() {
+            return Err(LlmError::invalid_request("prompt is empty"));
+        }
+
+        if self.params.echo {
+            // Cached tokens have no logits left, so nothing can be reused
+            self.truncate(0);
+            let top_n = self.params.logprobs.unwrap_or(0);
+            let logprobs = self.eval_tokens(&tokens, Some(top_n), true)?;
+            return Ok((tokens.len(), logprobs));
+        }
+
+        let mut n_reuse = self
+            .tokens
+            .iter()
+            .zip(tokens.iter())
+            .take_while(|(cached, new)| cached == new)
+            .count();
+        // The last prompt token is always decoded again so that its logits are available
+        if n_reuse == tokens.len() {
+            n_reuse -= 1;
+        }
+        let n_reuse = self.truncate(n_reuse);
+
+        self.eval(&tokens[n_reuse..])?;
+        Ok((tokens.len(), Vec::new()))
+    }
+
+    /// Samples up to `max_tokens` new tokens, passing each piece to `callback`.
+    ///
+    /// Every call starts with a fresh sampler: the grammar and the penalty
+    /// window only cover the tokens generated by this call.
+    /// Generation also ends at any of the session's stop strings, which are
+    /// left out of both the callback pieces and the returned text. The
+    /// session's cancellation token is checked before every decode step, a
+    /// cancelled generation returns what was produced so far. Use
+    /// `set_cancel` to give the next call a fresh token.
+    pub fn generate<F>(&mut self, max_tokens: i32, callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        let generation = self.start_generation(max_tokens)?;
+        self.run(generation, callback)
+    }
+
+    /// Sets the prompt and generates a completion for it using the session parameters
+    pub fn predict<F>(&mut self, prompt: &str, callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        let tokens = self.llm.str_to_tokens(prompt, true, true)?;
+        self.predict_tokens(tokens, callback)
+    }
+
+    /// Same as `predict` for a prompt that is already tokenized
+    pub(crate) fn predict_tokens<F>(&mut self, tokens: Vec<LlamaToken>, callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        let started = Instant::now();
+        let n_prompt_tokens = tokens.len();
+        let prompt_logprobs = match self.eval_prompt_tokens(tokens) {
+            Ok((_, prompt_logprobs)) => prompt_logprobs,
+            // A prompt abandoned half way produces an empty cancelled completion
+            Err(LlmError::Cancelled) => {
+                let mut generation = Generation::cancelled(self.params.max_tokens, &self.params.stop);
+                generation.record_prompt(n_prompt_tokens, started.elapsed());
+                return self.run(generation, callback);
+            }
+            Err(e) => return Err(e),
+        };
+        let mut generation = self.start_generation(self.params.max_tokens)?;
+        generation.record_prompt(n_prompt_tokens, started.elapsed());
+        let mut completion = self.run(generation, callback)?;
+        completion.prompt_logprobs = prompt_logprobs;
+        Ok(completion)
+    }
+
+    fn run<F>(&mut self, mut generation: Generation, mut callback: F) -> Result<Completion>
+    where
+        F: FnMut(&str),
+    {
+        while let Some((_, text)) = self.step(&mut generation)? {
+            if !text.is_empty() {
+                // Call the callback with the new text
+                callback(&text);
+            }
+        }
+
+        let (completion, remaining) = generation.into_completion();
+        if !remaining.is_empty() {
+            callback(&remaining);
+        }
+        Ok(completion)
+    }
+
+    /// Starts a generation with a fresh sampler, so a grammar completed by
+    /// an earlier turn constrains this one from its start again
+    pub(crate) fn start_generation(&mut self, max_tokens: i32) -> Result<Generation> {
+        if self.tokens.is_empty() {
+            return Err(LlmError::invalid_request("the session is empty, append a prompt before generating"));
+        }
+        self.sampler = Sampler::new(&self.params)?;
+        self.ensure_logits()?;
+        Ok(Generation::new(max_tokens, &self.params.stop))
+    }
+
+    /// Samples and decodes one token.
+    ///
+    /// Returns the token and the text it releases, which is empty while a
+    /// possible stop string is held back, or `None` once generation is over.
+    pub(crate) fn step(&mut self, generation: &mut Generation) -> Result<Option<(LlamaToken, String)>> {
+        if generation.reached_limit(self.is_cancelled()) {
+            return Ok(None);
+        }
+        // The sampled token is evaluated right away, so it needs a free cell
+        if !self.make_room(1)? {
+            generation.finish(FinishReason::Length);
+            return Ok(None);
+        }
+
+        let logits_idx = self.ensure_logits()?;
+        let candidates = self.ctx.candidates_ith(logits_idx);
+        let candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
+        let new_token_id = self.sampler.sample(&mut self.ctx, candidates_p);
+        self.sampler.accept(&mut self.ctx, new_token_id);
+
+        // Check for end of generation
+        if self.llm.model.is_eog_token(new_token_id) {
+            generation.finish(FinishReason::Stop);
+            return Ok(None);
+        }
+
+        let logprob = match self.params.logprobs {
+            Some(top_n) => {
+                let logits = self.ctx.get_logits_ith(logits_idx);
+                Some(self.llm.token_logprob(logits, new_token_id, top_n)?)
+            }
+            None => None,
+        };
+        let released = generation.push(self.llm, new_token_id, logprob)?;
+
+        // Process next token
+        self.eval(&[new_token_id])?;
+
+        Ok(Some((new_token_id, released)))
+    }
+
+    /// Returns the batch index of the last token's logits, decoding that token again if it has none
+    fn ensure_logits(&mut self) -> Result<i32> {
+        if let Some(logits_idx) = self.logits_idx {
+            return Ok(logits_idx);
+        }
+        if self.tokens.is_empty() {
+            return Err(LlmError::invalid_request("the session is empty, append a prompt before generating"));
+        }
+        let tokens = self.tokens.clone();
+        let n_keep = self.truncate(tokens.len() - 1);
+        self.eval_tokens(&tokens[n_keep..], None, false)?;
+        Ok(self.logits_idx.unwrap())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.params
+            .cancel
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Drops every cached token from position `n` onwards, returning how many tokens are kept
+    pub(crate) fn truncate(&mut self, n: usize) -> usize {
+        if n >= self.tokens.len() {
+            return self.tokens.len();
+        }
+        if !self.ctx.clear_kv_cache_seq(Some(0), Some(n as u32), None) {
+            // Partial removal is not supported by every cache type, start over instead
+            self.ctx.clear_kv_cache();
+            self.tokens.clear();
+            self.logits_idx = None;
+            return 0;
+        }
+        self.tokens.truncate(n);
+        self.logits_idx = None;
+        n
+    }
+
+    /// Frees space for `n` more tokens according to the overflow policy.
+    ///
+    /// Returns `false` when the policy is to stop instead.
+    fn make_room(&mut self, n: usize) -> Result<bool> {
+        let n_ctx = self.ctx.n_ctx() as usize;
+        if self.tokens.len() + n <= n_ctx {
+            return Ok(true);
+        }
+        match self.params.context_overflow {
+            ContextOverflow::Error => Err(LlmError::ContextOverflow {
+                n_tokens: self.tokens.len() + n,
+                n_ctx: n_ctx as u32,
+            }),
+            ContextOverflow::Stop => Ok(false),
+            ContextOverflow::Shift { n_keep } => {
+                self.shift(n_keep, self.tokens.len() + n - n_ctx)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Drops at least `n_min` tokens following the first `n_keep` and moves
+    /// the remaining ones back so the positions stay contiguous
+    fn shift(&mut self, n_keep: usize, n_min: usize) -> Result<()> {
+        let n_keep = n_keep.min(self.tokens.len());
+        let n_left = self.tokens.len() - n_keep;
+        let n_discard = (n_left / 2).max(n_min);
+        if n_discard > n_left {
+            return Err(LlmError::ContextOverflow {
+                n_tokens: self.ctx.n_ctx() as usize + n_min,
+                n_ctx: self.ctx.n_ctx(),
+            });
+        }
+
+        let start = n_keep as u32;
+        let end = (n_keep + n_discard) as u32;
+        if !self.ctx.clear_kv_cache_seq(Some(0), Some(start), Some(end)) {
+            return Err(LlmError::llama("the KV cache does not support removing tokens, context shifting is unavailable"));
+        }
+        self.ctx
+            .kv_cache_seq_add(0, Some(end), None, -(n_discard as i32))
+            .llama_context(|| "failed to shift the KV cache".to_string())?;
+        self.tokens.drain(n_keep..n_keep + n_discard);
+        Ok(())
+    }
+
+    /// Decodes `tokens` at the end of the session, requesting logits for the last one
+    pub(crate) fn eval(&mut self, tokens: &[LlamaToken]) -> Result<()> {
+        self.eval_tokens(tokens, None, true).map(|_| ())
+    }
+
+    /// Decodes `tokens` at the end of the session.
+    ///
+    /// Tokens are submitted in chunks of at most `n_batch` so prompts longer
+    /// than a single batch can still be evaluated. With `echo_top_n` set,
+    /// logits are requested for every token and the log-probability of each
+    /// token after the first is returned. With `cancellable` set, the
+    /// cancellation token is checked between chunks and a cancelled call
+    /// removes the tokens it already decoded before failing with
+    /// `LlmError::Cancelled`.
+    fn eval_tokens(&mut self, tokens: &[LlamaToken], echo_top_n: Option<usize>, cancellable: bool) -> Result<Vec<TokenLogprob>> {
+        let n_batch = self.params.n_batch.max(1) as usize;
+        let mut batch = LlamaBatch::new(n_batch.min(tokens.len()), 1);
+        let last_index = tokens.len() - 1;
+        let mut logprobs = Vec::new();
+        let n_start = self.tokens.len();
+
+        for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+            // Long prompts can be abandoned between chunks
+            if cancellable && chunk_index > 0 && self.is_cancelled() {
+                // Shifting may have dropped earlier tokens, so `n_start` is an upper bound
+                self.truncate(n_start.min(self.tokens.len()));
+                return Err(LlmError::Cancelled);
+            }
+            let offset = chunk_index * n_batch;
+            if !self.make_room(chunk.len())? {
+                return Err(LlmError::ContextOverflow {
+                    n_tokens: tokens.len(),
+                    n_ctx: self.ctx.n_ctx(),
+                });
+            }
+            let n_past = self.tokens.len() as i32;
+            batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                let logits = echo_top_n.is_some() || offset + i == last_index;
+                batch.add(*token, n_past + i as i32, &[0], logits)?;
+            }
+
+            self.ctx.decode(&mut batch)?;
+            self.tokens.extend_from_slice(chunk);
+
+            if let Some(top_n) = echo_top_n {
+                // The logits at each position score the token that follows it
+                for i in 0..chunk.len() {
+                    if let Some(next) = tokens.get(offset + i + 1) {
+                        let logits = self.ctx.get_logits_ith(i as i32);
+                        logprobs.push(self.llm.token_logprob(logits, *next, top_n)?);
+                    }
+                }
+            }
+        }
+
+        self.logits_idx = Some(batch.n_tokens() - 1);
+        Ok(logprobs)
+    }
+}
+
+/// State of one generation running in a session
+pub(crate) struct Generation {
+    max_tokens: i32,
+    n_decode: i32,
+    decoder: Decoder,
+    stop: StopMatcher,
+    // Whether a stop string ended the generation
+    stopped: bool,
+    finish_reason: Option<FinishReason>,
+    output: String,
+    logprobs: Vec<TokenLogprob>,
+    // Byte offset at which the text of each generated token ends, before stop strings are removed
+    piece_ends: Vec<usize>,
+    started: Instant,
+    stats: GenerationStats,
+}
+
+impl Generation {
+    /// Generation that is over before it starts, because its prompt evaluation was cancelled
+    pub(crate) fn cancelled(max_tokens: i32, stop: &[String]) -> Self {
+        let mut generation = Self::new(max_tokens, stop);
+        generation.finish(FinishReason::Cancelled);
+        generation
+    }
+
+    pub(crate) fn new(max_tokens: i32, stop: &[String]) -> Self {
+        Self {
+            max_tokens,
+            n_decode: 0,
+            decoder: UTF_8.new_decoder(),
+            stop: StopMatcher::new(stop),
+            stopped: false,
+            finish_reason: None,
+            output: String::new(),
+            logprobs: Vec::new(),
+            piece_ends: Vec::new(),
+            started: Instant::now(),
+            stats: GenerationStats::default(),
+        }
+    }
+
+    /// Records the prompt evaluation that preceded this generation, which starts now
+    pub(crate) fn record_prompt(&mut self, n_prompt_tokens: usize, prompt_eval_time: Duration) {
+        self.stats.n_prompt_tokens = n_prompt_tokens;
+        self.stats.prompt_eval_time = prompt_eval_time;
+        self.started = Instant::now();
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    /// Ends the generation for `reason` unless it is already over
+    pub(crate) fn finish(&mut self, reason: FinishReason) {
+        self.finish_reason.get_or_insert(reason);
+    }
+
+    /// Ends the generation once `max_tokens` is reached or when `cancelled`
+    /// is set, returning whether it is over
+    pub(crate) fn reached_limit(&mut self, cancelled: bool) -> bool {
+        if self.n_decode >= self.max_tokens {
+            self.finish(FinishReason::Length);
+        } else if cancelled {
+            self.finish(FinishReason::Cancelled);
+        }
+        self.is_finished()
+    }
+
+    /// Records a sampled token and returns the text it releases.
+    ///
+    /// A completed stop string ends the generation.
+    pub(crate) fn push(&mut self, llm: &LLM, token: LlamaToken, logprob: Option<TokenLogprob>) -> Result<String> {
+        // Decode token to string
+        let output_bytes = llm.model.token_to_bytes(token, Special::Tokenize)?;
+        let mut token_string = String::with_capacity(32);
+        self.decoder.decode_to_string(&output_bytes, &mut token_string, false);
+        Ok(self.push_piece(&token_string, logprob))
+    }
+
+    /// Records the text of a generated token and returns the text it releases
+    pub(crate) fn push_piece(&mut self, piece: &str, logprob: Option<TokenLogprob>) -> String {
+        self.logprobs.extend(logprob);
+        if self.n_decode == 0 {
+            self.stats.time_to_first_token = self.stats.prompt_eval_time + self.started.elapsed();
+        }
+
+        let end = self.piece_ends.last().copied().unwrap_or(0) + piece.len();
+        self.piece_ends.push(end);
+        let (released, matched) = self.stop.push(piece);
+        self.output.push_str(&released);
+        self.n_decode += 1;
+
+        if matched {
+            self.stopped = true;
+            self.finish(FinishReason::Stop);
+        }
+        released
+    }
+
+    /// Builds the completion, also returning text that was still held back and is released now
+    pub(crate) fn into_completion(mut self) -> (Completion, String) {
+        let mut remaining = String::new();
+        if self.stopped {
+            // Tokens that only produced the stop string are not part of the output
+            let kept = std::iter::once(0)
+                .chain(self.piece_ends.iter().copied())
+                .take(self.piece_ends.len())
+                .filter(|&start| start < self.output.len())
+                .count();
+            self.logprobs.truncate(kept);
+        } else {
+            remaining = self.stop.flush();
+            self.output.push_str(&remaining);
+        }
+
+        let generation_time = self.started.elapsed();
+        let stats = GenerationStats {
+            n_generated_tokens: self.n_decode as usize,
+            generation_time,
+            tokens_per_second: self.n_decode as f32 / generation_time.as_secs_f32().max(f32::EPSILON),
+            ..self.stats
+        };
+        let completion = Completion {
+            text: self.output,
+            finish_reason: self.finish_reason.unwrap_or(FinishReason::Length),
+            logprobs: self.logprobs,
+            stats,
+            ..Default::default()
+        };
+        (completion, remaining)
+    }
+}
@@ -0,0 +1,244 @@
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+use llama_cpp_2::token::LlamaToken;
+use std::time::Instant;
+
+use crate::completion::CancellationToken;
+use crate::sampling::Sampler;
+use crate::session::Generation;
+use crate::{Completion, ContextOverflow, FinishReason, InferenceParams, LlmError, Result, LLM};
+
+// Largest vocabulary size difference tolerated between target and draft, as in llama.cpp
+const MAX_VOCAB_SIZE_DIFFERENCE: i32 = 128;
+
+/// Counters reported by `LLM::predict_speculative`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpeculativeStats {
+    /// Tokens proposed by the draft model
+    pub n_drafted: usize,
+    /// Proposed tokens the target model agreed with
+    pub n_accepted: usize,
+}
+
+impl SpeculativeStats {
+    /// Share of the drafted tokens that were accepted, `0.0` when nothing was drafted
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.n_drafted == 0 {
+            return 0.0;
+        }
+        self.n_accepted as f32 / self.n_drafted as f32
+    }
+}
+
+impl LLM {
+    /// Generates a completion using `draft` to propose tokens for this model.
+    ///
+    /// In every step the draft model greedily proposes up to `n_draft`
+    /// tokens, which this model then evaluates in a single batch. Tokens are
+    /// sampled from this model's logits at each drafted position with the
+    /// regular sampler chain, and the proposals are accepted for as long as
+    /// they match what was sampled. The output therefore follows this model
+    /// alone, with greedy sampling it is the same as `predict`, while every
+    /// accepted token saves a full decode of the larger model.
+    ///
+    /// The draft model must share this model's tokenizer. LoRA adapters are
+    /// only applied to this model and `InferenceParams::echo` is not supported.
+    /// Fewer tokens are drafted as the context fills up, once it is full
+    /// `ContextOverflow` applies as in `predict`, except that context
+    /// shifting is not supported.
+    pub fn predict_speculative<F>(
+        &self,
+        draft: &LLM,
+        prompt: &str,
+        inference_params: InferenceParams,
+        n_draft: usize,
+        mut callback: F,
+    ) -> Result<(Completion, SpeculativeStats)>
+    where
+        F: FnMut(&str),
+    {
+        self.check_draft_compatible(draft)?;
+        if inference_params.echo {
+            return Err(LlmError::invalid_request("echo is not supported with speculative decoding"));
+        }
+        if let ContextOverflow::Shift { .. } = inference_params.context_overflow {
+            return Err(LlmError::invalid_request("context shifting is not supported by predict_speculative"));
+        }
+
+        let tokens = self.str_to_tokens(prompt, true, true)?;
+        if tokens.is_empty() {
+            return Err(LlmError::invalid_request("prompt is empty"));
+        }
+
+        // The target verifies the pending token and the drafts in one batch
+        let n_batch = inference_params.n_batch as usize;
+        if n_batch < 2 {
+            return Err(LlmError::invalid_request("speculative decoding needs an n_batch of at least 2"));
+        }
+        let n_draft = n_draft.clamp(1, n_batch - 1);
+
+        let draft_params = InferenceParams {
+            lora_adapters: Vec::new(),
+            ..inference_params.clone()
+        };
+        let mut ctx = self.new_context(&inference_params)?;
+        let mut draft_ctx = draft.new_context(&draft_params)?;
+        let n_ctx = ctx.n_ctx().min(draft_ctx.n_ctx()) as usize;
+        if tokens.len() > n_ctx {
+            return Err(LlmError::ContextOverflow {
+                n_tokens: tokens.len(),
+                n_ctx: n_ctx as u32,
+            });
+        }
+        let mut sampler = Sampler::new(&inference_params)?;
+        let mut generation = Generation::new(inference_params.max_tokens, &inference_params.stop);
+        let mut stats = SpeculativeStats::default();
+
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        // Tokens evaluated by the target, and the last sampled token which is not evaluated yet
+        let (prompt_tokens, last) = tokens.split_last().unwrap();
+        let mut committed = prompt_tokens.to_vec();
+        let mut last = *last;
+        let mut n_draft_cached = 0;
+        let started = Instant::now();
+        decode_tokens(&mut ctx, &mut batch, &committed, 0, n_batch)?;
+        generation.record_prompt(tokens.len(), started.elapsed());
+
+        loop {
+            let cancelled = inference_params
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled);
+            if generation.reached_limit(cancelled) {
+                break;
+            }
+
+            // The pending token needs a cell in both caches, the drafts only get the cells left over
+            let n_free = n_ctx.saturating_sub(committed.len());
+            if n_free == 0 {
+                if inference_params.context_overflow == ContextOverflow::Stop {
+                    generation.finish(FinishReason::Length);
+                    break;
+                }
+                return Err(LlmError::ContextOverflow {
+                    n_tokens: committed.len() + 1,
+                    n_ctx: n_ctx as u32,
+                });
+            }
+            let n_draft_step = n_draft.min(n_free - 1);
+
+            // Bring the draft up to date and let it propose tokens greedily
+            let mut pending = committed[n_draft_cached..].to_vec();
+            pending.push(last);
+            decode_tokens(&mut draft_ctx, &mut batch, &pending, n_draft_cached, n_batch)?;
+            n_draft_cached = committed.len() + 1;
+            let mut drafted = Vec::with_capacity(n_draft_step);
+            while n_draft_step > 0 {
+                let candidates = LlamaTokenDataArray::from_iter(draft_ctx.candidates_ith(batch.n_tokens() - 1), false);
+                let token = draft_ctx.sample_token_greedy(candidates);
+                if draft.model.is_eog_token(token) || token.0 >= self.model.n_vocab() {
+                    break;
+                }
+                drafted.push(token);
+                if drafted.len() == n_draft_step {
+                    break;
+                }
+                decode_tokens(&mut draft_ctx, &mut batch, &[token], n_draft_cached, n_batch)?;
+                n_draft_cached += 1;
+            }
+
+            // Evaluate the pending token and the drafts with the target
+            let n_past = committed.len() as i32;
+            batch.clear();
+            batch.add(last, n_past, &[0], true)?;
+            for (i, token) in drafted.iter().enumerate() {
+                batch.add(*token, n_past + 1 + i as i32, &[0], true)?;
+            }
+            ctx.decode(&mut batch)?;
+            committed.push(last);
+
+            let mut n_accepted = 0;
+            for i in 0..=drafted.len() {
+                let candidates = LlamaTokenDataArray::from_iter(ctx.candidates_ith(i as i32), false);
+                let token = sampler.sample(&mut ctx, candidates);
+                sampler.accept(&mut ctx, token);
+
+                if self.model.is_eog_token(token) {
+                    generation.finish(FinishReason::Stop);
+                    break;
+                }
+                let logprob = match inference_params.logprobs {
+                    Some(top_n) => Some(self.token_logprob(ctx.get_logits_ith(i as i32), token, top_n)?),
+                    None => None,
+                };
+                let released = generation.push(self, token, logprob)?;
+                if !released.is_empty() {
+                    callback(&released);
+                }
+
+                last = token;
+                if generation.reached_limit(false) || drafted.get(i) != Some(&token) {
+                    break;
+                }
+                // The draft matched, its cache entry in the target is kept
+                committed.push(token);
+                n_accepted += 1;
+            }
+            stats.n_drafted += drafted.len();
+            stats.n_accepted += n_accepted;
+
+            // Drop the rejected drafts from both caches
+            let n_keep = committed.len() as u32;
+            if !ctx.clear_kv_cache_seq(Some(0), Some(n_keep), None)
+                || !draft_ctx.clear_kv_cache_seq(Some(0), Some(n_keep), None)
+            {
+                return Err(LlmError::llama("the KV cache does not support removing rejected draft tokens"));
+            }
+            n_draft_cached = n_draft_cached.min(committed.len());
+        }
+
+        let (completion, remaining) = generation.into_completion();
+        if !remaining.is_empty() {
+            callback(&remaining);
+        }
+        Ok((completion, stats))
+    }
+
+    /// Checks that `draft` tokenizes text the same way as this model
+    fn check_draft_compatible(&self, draft: &LLM) -> Result<()> {
+        let n_vocab = self.model.n_vocab();
+        let n_vocab_draft = draft.model.n_vocab();
+        if (n_vocab - n_vocab_draft).abs() > MAX_VOCAB_SIZE_DIFFERENCE {
+            return Err(LlmError::InvalidRequest(format!(
+                "the draft model vocabulary has {} tokens but the target has {}",
+                n_vocab_draft, n_vocab
+            )));
+        }
+        if self.model.token_bos() != draft.model.token_bos() || self.model.token_eos() != draft.model.token_eos() {
+            return Err(LlmError::invalid_request("the draft model uses different special tokens than the target"));
+        }
+        Ok(())
+    }
+}
+
+/// Decodes `tokens` from position `start` in chunks of `n_batch`, requesting
+/// logits for the last token only
+fn decode_tokens(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    tokens: &[LlamaToken],
+    start: usize,
+    n_batch: usize,
+) -> Result<()> {
+    for (chunk_index, chunk) in tokens.chunks(n_batch).enumerate() {
+        let offset = start + chunk_index * n_batch;
+        batch.clear();
+        for (i, token) in chunk.iter().enumerate() {
+            let logits = offset + i + 1 == start + tokens.len();
+            batch.add(*token, (offset + i) as i32, &[0], logits)?;
+        }
+        ctx.decode(batch)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use llama_cpp_2::token::LlamaToken;
+use std::path::Path;
+
+use crate::sampling::Sampler;
+use crate::{InferenceParams, LlmError, LlmSession, Result, LLM};
+
+const MAGIC: &[u8; 8] = b"LLMSTATE";
+const VERSION: u32 = 1;
+// Magic, version, model fingerprint, token count and state size
+const HEADER_LEN: usize = 8 + 4 + 8 + 4 + 8;
+const CHECKSUM_LEN: usize = 8;
+
+impl LlmSession<'_> {
+    /// Serializes the KV cache together with the evaluated tokens.
+    ///
+    /// The snapshot records a fingerprint of the model and of the selected
+    /// LoRA adapters, and a checksum of its content. Sampler state such as
+    /// grammar progress is not included.
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        let mut state = vec![0u8; self.ctx.get_state_size()];
+        // SAFETY: the buffer has the size llama.cpp reports for the state
+        let written = unsafe { self.ctx.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(written);
+        Ok(encode_state(self.fingerprint(), &self.tokens, &state))
+    }
+
+    /// Restores a snapshot taken with `save_state`, replacing the session content.
+    ///
+    /// The snapshot must come from the same model with the same LoRA
+    /// adapters and fit in this session's `n_ctx`. The sampler starts over.
+    pub fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let (tokens, state) = decode_state(data, self.fingerprint(), self.ctx.n_ctx())?;
+
+        // SAFETY: the state was written by `copy_state_data` for this model, which the fingerprint checks
+        let read = unsafe { self.ctx.set_state_data(state) };
+        if read != state.len() {
+            self.reset()?;
+            return Err(LlmError::InvalidState(format!(
+                "llama.cpp read {} of {} state bytes",
+                read,
+                state.len()
+            )));
+        }
+
+        self.tokens = tokens.clone();
+        self.sampler = Sampler::new(&self.params)?;
+        // Decode the last token again so its logits are available to sample from
+        if !tokens.is_empty() {
+            let n_keep = self.truncate(tokens.len() - 1);
+            self.eval(&tokens[n_keep..])?;
+        }
+        Ok(())
+    }
+
+    /// Writes `save_state` to `path`
+    pub fn save_state_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.save_state()?).map_err(|source| LlmError::Write {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Restores a snapshot written by `save_state_file`
+    pub fn restore_state_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|source| LlmError::read(path, source))?;
+        self.restore_state(&data)
+    }
+
+    /// Identifies the model and adapters whose KV cache the state holds
+    fn fingerprint(&self) -> u64 {
+        let info = self.llm.info();
+        let mut identity = format!(
+            "{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            info.architecture,
+            info.name.unwrap_or_default(),
+            info.n_params,
+            info.n_vocab,
+            info.n_embd,
+            info.n_layer,
+            info.quantization,
+            info.file_size,
+            info.sha256
+        );
+        for (name, scale) in &self.params.lora_adapters {
+            identity.push_str(&format!("|{}={}", name, scale));
+        }
+        fnv1a(identity.as_bytes())
+    }
+}
+
+impl LLM {
+    /// Creates a session from a snapshot written by `LlmSession::save_state_file`
+    pub fn restore_session(&self, path: impl AsRef<Path>, inference_params: InferenceParams) -> Result<LlmSession<'_>> {
+        let mut session = self.session(inference_params)?;
+        session.restore_state_file(path)?;
+        Ok(session)
+    }
+}
+
+/// Lays out a snapshot: header, tokens, llama.cpp state and a checksum of all of it
+fn encode_state(fingerprint: u64, tokens: &[LlamaToken], state: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + tokens.len() * 4 + state.len() + CHECKSUM_LEN);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&fingerprint.to_le_bytes());
+    data.extend_from_slice(&(tokens.len() as u32).to_le_bytes());
+    for token in tokens {
+        data.extend_from_slice(&token.0.to_le_bytes());
+    }
+    data.extend_from_slice(&(state.len() as u64).to_le_bytes());
+    data.extend_from_slice(state);
+    let checksum = fnv1a(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// Checks a snapshot against the session's fingerprint and `n_ctx` and
+/// splits it into its tokens and llama.cpp state
+fn decode_state(data: &[u8], fingerprint: u64, n_ctx: u32) -> Result<(Vec<LlamaToken>, &[u8])> {
+    let corrupt = || LlmError::InvalidState("the snapshot is truncated or corrupt".to_string());
+    if data.len() < HEADER_LEN + CHECKSUM_LEN || &data[..8] != MAGIC {
+        return Err(LlmError::InvalidState("not a session snapshot".to_string()));
+    }
+    let (content, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    if fnv1a(content).to_le_bytes() != checksum {
+        return Err(corrupt());
+    }
+
+    let mut reader = Reader(&content[8..]);
+    let version = u32::from_le_bytes(reader.take()?);
+    if version != VERSION {
+        return Err(LlmError::InvalidState(format!("unsupported snapshot version {}", version)));
+    }
+    if u64::from_le_bytes(reader.take()?) != fingerprint {
+        return Err(LlmError::InvalidState(
+            "the snapshot was taken with a different model or LoRA adapters".to_string(),
+        ));
+    }
+
+    let n_tokens = u32::from_le_bytes(reader.take()?) as usize;
+    if n_tokens > n_ctx as usize {
+        return Err(LlmError::ContextOverflow { n_tokens, n_ctx });
+    }
+    let tokens = (0..n_tokens)
+        .map(|_| reader.take().map(|bytes| LlamaToken::new(i32::from_le_bytes(bytes))))
+        .collect::<Result<Vec<_>>>()?;
+    let state_len = u64::from_le_bytes(reader.take()?) as usize;
+    if reader.0.len() != state_len {
+        return Err(corrupt());
+    }
+    Ok((tokens, reader.0))
+}
+
+/// Reads fixed-size fields from the front of a snapshot
+struct Reader<'d>(&'d [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.0.len() < N {
+            return Err(LlmError::InvalidState("the snapshot is truncated or corrupt".to_string()));
+        }
+        let (head, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(head.try_into().unwrap())
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and Rust versions
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: u64 = 0x0123_4567_89ab_cdef;
+
+    fn tokens() -> Vec<LlamaToken> {
+        [1, 15043, 3186].into_iter().map(LlamaToken::new).collect()
+    }
+
+    fn assert_invalid(result: Result<(Vec<LlamaToken>, &[u8])>, expected: &str) {
+        match result {
+            Err(LlmError::InvalidState(message)) => assert!(message.contains(expected), "{}", message),
+            other => panic!("expected InvalidState, got {:?}", other.map(|(tokens, _)| tokens)),
+        }
+    }
+
+    /// Replaces the checksum after a test edited the content
+    fn reseal(data: &mut [u8]) {
+        let (content, checksum) = data.split_at_mut(data.len() - CHECKSUM_LEN);
+        checksum.copy_from_slice(&fnv1a(content).to_le_bytes());
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn round_trips_tokens_and_state() {
+        let data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        assert_eq!(data.len(), HEADER_LEN + 3 * 4 + 8 + CHECKSUM_LEN);
+        let (decoded, state) = decode_state(&data, FINGERPRINT, 512).unwrap();
+        assert_eq!(decoded, tokens());
+        assert_eq!(state, b"kv cache");
+    }
+
+    #[test]
+    fn round_trips_an_empty_session() {
+        let data = encode_state(FINGERPRINT, &[], &[]);
+        let (decoded, state) = decode_state(&data, FINGERPRINT, 512).unwrap();
+        assert!(decoded.is_empty());
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_invalid(decode_state(b"", FINGERPRINT, 512), "not a session snapshot");
+        let mut data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        data[0] = b'X';
+        assert_invalid(decode_state(&data, FINGERPRINT, 512), "not a session snapshot");
+    }
+
+    #[test]
+    fn rejects_corrupt_and_truncated_snapshots() {
+        let data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        let mut flipped = data.clone();
+        flipped[HEADER_LEN] ^= 1;
+        assert_invalid(decode_state(&flipped, FINGERPRINT, 512), "corrupt");
+
+        let mut truncated = data[..data.len() - CHECKSUM_LEN - 1].to_vec();
+        truncated.extend_from_slice(&[0; CHECKSUM_LEN]);
+        reseal(&mut truncated);
+        assert_invalid(decode_state(&truncated, FINGERPRINT, 512), "corrupt");
+    }
+
+    #[test]
+    fn rejects_a_truncated_token_list() {
+        let mut data = encode_state(FINGERPRINT, &tokens(), &[]);
+        // Claim more tokens than the snapshot holds
+        data[20..24].copy_from_slice(&100u32.to_le_bytes());
+        reseal(&mut data);
+        assert_invalid(decode_state(&data, FINGERPRINT, 512), "corrupt");
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        data[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        reseal(&mut data);
+        assert_invalid(decode_state(&data, FINGERPRINT, 512), "unsupported snapshot version 2");
+    }
+
+    #[test]
+    fn rejects_other_models() {
+        let data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        assert_invalid(decode_state(&data, FINGERPRINT + 1, 512), "different model");
+    }
+
+    #[test]
+    fn rejects_snapshots_larger_than_the_context() {
+        let data = encode_state(FINGERPRINT, &tokens(), b"kv cache");
+        match decode_state(&data, FINGERPRINT, 2) {
+            Err(LlmError::ContextOverflow { n_tokens, n_ctx }) => assert_eq!((n_tokens, n_ctx), (3, 2)),
+            other => panic!("expected ContextOverflow, got {:?}", other.map(|(tokens, _)| tokens)),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/// Detects stop strings in streamed text.
+///
+/// Text that could still turn out to be the beginning of a stop string is
+/// held back until the match is confirmed or ruled out, so callers never see
+/// any part of a stop string.
+pub(crate) struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub(crate) fn new(stops: &[String]) -> Self {
+        Self {
+            stops: stops.iter().filter(|stop| !stop.is_empty()).cloned().collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Feeds the next generated piece.
+    ///
+    /// Returns the text that can be released and whether a stop string was
+    /// matched, in which case generation should end and the text after the
+    /// match is discarded.
+    pub(crate) fn push(&mut self, piece: &str) -> (String, bool) {
+        if self.stops.is_empty() {
+            return (piece.to_string(), false);
+        }
+        self.pending.push_str(piece);
+
+        // Earliest match wins when several stop strings occur
+        let matched = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(index) = matched {
+            let released = self.pending[..index].to_string();
+            self.pending.clear();
+            return (released, true);
+        }
+
+        let held = self.partial_match_len();
+        let split = self.pending.len() - held;
+        let released = self.pending[..split].to_string();
+        self.pending.drain(..split);
+        (released, false)
+    }
+
+    /// Releases whatever is still held back once generation ends for another reason
+    pub(crate) fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Length of the longest suffix of the pending text that starts a stop string
+    fn partial_match_len(&self) -> usize {
+        let pending = self.pending.as_str();
+        (1..=pending.len())
+            .rev()
+            .filter(|&len| pending.is_char_boundary(pending.len() - len))
+            .find(|&len| {
+                let suffix = &pending[pending.len() - len..];
+                self.stops.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StopMatcher;
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        StopMatcher::new(&stops.iter().map(|stop| stop.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn releases_everything_without_stop_strings() {
+        let mut stop = matcher(&[]);
+        assert_eq!(stop.push("Hello"), ("Hello".to_string(), false));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn ignores_empty_stop_strings() {
+        let mut stop = matcher(&[""]);
+        assert_eq!(stop.push("text"), ("text".to_string(), false));
+    }
+
+    #[test]
+    fn matches_stop_string_split_across_pieces() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("answer #"), ("answer ".to_string(), false));
+        assert_eq!(stop.push("#"), (String::new(), false));
+        assert_eq!(stop.push("# trailing"), (String::new(), true));
+    }
+
+    #[test]
+    fn releases_held_text_once_the_match_is_ruled_out() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("a ##"), ("a ".to_string(), false));
+        assert_eq!(stop.push("b"), ("##b".to_string(), false));
+    }
+
+    #[test]
+    fn earliest_match_wins() {
+        let mut stop = matcher(&["world", "lo"]);
+        assert_eq!(stop.push("hello world"), ("hel".to_string(), true));
+    }
+
+    #[test]
+    fn holds_back_whole_multibyte_characters() {
+        let mut stop = matcher(&["éx"]);
+        assert_eq!(stop.push("café"), ("caf".to_string(), false));
+        assert_eq!(stop.push("y"), ("éy".to_string(), false));
+    }
+
+    #[test]
+    fn matches_stop_string_starting_with_multibyte_character() {
+        let mut stop = matcher(&["éx"]);
+        assert_eq!(stop.push("caf"), ("caf".to_string(), false));
+        assert_eq!(stop.push("é"), (String::new(), false));
+        assert_eq!(stop.push("x!"), (String::new(), true));
+    }
+
+    #[test]
+    fn flush_releases_pending_text() {
+        let mut stop = matcher(&["</s>"]);
+        assert_eq!(stop.push("end </"), ("end ".to_string(), false));
+        assert_eq!(stop.flush(), "</");
+        assert_eq!(stop.flush(), "");
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use crate::logprobs::TokenLogprob;
+use crate::session::Generation;
+use crate::{Completion, InferenceParams, LlmError, LlmSession, Result, LLM};
+
+/// One step of a streamed generation
+#[derive(Debug, Clone)]
+pub struct TokenEvent {
+    /// Text released at this step, empty while a possible stop string is held back
+    pub text: String,
+    /// Token sampled at this step, `None` on the final event
+    pub token: Option<u32>,
+    /// Set on the final event, which also releases any text still held back
+    pub completion: Option<Completion>,
+}
+
+/// Pull-based generation, returned by `LLM::stream`.
+///
+/// Every call to `next` decodes one token. The iterator ends after the event
+/// that carries the `Completion`.
+pub struct TokenStream<'a> {
+    session: LlmSession<'a>,
+    generation: Option<Generation>,
+    prompt_logprobs: Vec<TokenLogprob>,
+}
+
+impl Iterator for TokenStream<'_> {
+    type Item = Result<TokenEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let generation = self.generation.as_mut()?;
+        match self.session.step(generation) {
+            Ok(Some((token, text))) => Some(Ok(TokenEvent {
+                text,
+                token: Some(token.0 as u32),
+                completion: None,
+            })),
+            Ok(None) => {
+                let (mut completion, text) = self.generation.take()?.into_completion();
+                completion.prompt_logprobs = std::mem::take(&mut self.prompt_logprobs);
+                Some(Ok(TokenEvent {
+                    text,
+                    token: None,
+                    completion: Some(completion),
+                }))
+            }
+            Err(e) => {
+                self.generation = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl LLM {
+    /// Evaluates `prompt` and returns an iterator that generates one token per call
+    pub fn stream(&self, prompt: &str, inference_params: InferenceParams) -> Result<TokenStream<'_>> {
+        let max_tokens = inference_params.max_tokens;
+        let started = Instant::now();
+        let mut session = self.session(inference_params)?;
+        let tokens = self.str_to_tokens(prompt, true, true)?;
+        let n_prompt_tokens = tokens.len();
+        let (mut generation, prompt_logprobs) = match session.eval_prompt_tokens(tokens) {
+            Ok((_, prompt_logprobs)) => (session.start_generation(max_tokens)?, prompt_logprobs),
+            // The first event then carries an empty cancelled completion
+            Err(LlmError::Cancelled) => (Generation::cancelled(max_tokens, &session.params.stop), Vec::new()),
+            Err(e) => return Err(e),
+        };
+        generation.record_prompt(n_prompt_tokens, started.elapsed());
+        Ok(TokenStream {
+            session,
+            generation: Some(generation),
+            prompt_logprobs,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+mod async_stream {
+    use futures::Stream;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::TokenEvent;
+    use crate::{InferenceParams, Result, LLM};
+
+    // Events decoded ahead of the consumer before the decoding thread waits
+    const CHANNEL_CAPACITY: usize = 32;
+
+    impl LLM {
+        /// Async version of `stream`.
+        ///
+        /// Decoding runs on a Tokio blocking thread and feeds a bounded
+        /// channel, so this must be called from within a Tokio runtime.
+        /// Dropping the returned stream stops generation at the next token.
+        pub fn stream_async(
+            self: Arc<Self>,
+            prompt: String,
+            inference_params: InferenceParams,
+        ) -> impl Stream<Item = Result<TokenEvent>> {
+            let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+            tokio::task::spawn_blocking(move || {
+                let stream = match self.stream(&prompt, inference_params) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(e));
+                        return;
+                    }
+                };
+                for event in stream {
+                    // Sending fails once the receiving stream has been dropped
+                    if tx.blocking_send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            ReceiverStream::new(rx)
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use encoding_rs::UTF_8;
+use llama_cpp_2::model::{AddBos, Special};
+use llama_cpp_2::token::LlamaToken;
+use llama_cpp_sys_2::llama_token;
+use std::ffi::c_int;
+use std::ptr::NonNull;
+
+use crate::{LlmError, Result, LLM};
+
+impl LLM {
+    /// Converts `text` to token ids.
+    ///
+    /// With `parse_special` unset, text that spells out a special token such
+    /// as `<|eot_id|>` is tokenized as plain text instead of becoming the
+    /// control token itself.
+    pub fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<u32>> {
+        let tokens = self.str_to_tokens(text, add_bos, parse_special)?;
+        Ok(tokens.into_iter().map(|token| token.0 as u32).collect())
+    }
+
+    /// Converts token ids back to text.
+    ///
+    /// The bytes of all tokens are joined before UTF-8 decoding, so characters
+    /// split across several tokens come out whole.
+    pub fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        let mut bytes = Vec::with_capacity(tokens.len() * 4);
+        for token in self.to_llama_tokens(tokens)? {
+            bytes.extend(self.model.token_to_bytes(token, Special::Tokenize)?);
+        }
+        let (text, _) = UTF_8.decode_without_bom_handling(&bytes);
+        Ok(text.into_owned())
+    }
+
+    /// Number of tokens `text` uses as a prompt, including the BOS token
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(self.str_to_tokens(text, true, true)?.len())
+    }
+
+    pub(crate) fn str_to_tokens(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<LlamaToken>> {
+        if parse_special {
+            let add_bos = if add_bos { AddBos::Always } else { AddBos::Never };
+            return Ok(self.model.str_to_token(text, add_bos)?);
+        }
+
+        // llama-cpp-2 always parses special tokens, plain text is tokenized by llama.cpp directly
+        let text_len = c_int::try_from(text.len()).map_err(|e| LlmError::Tokenize(Box::new(e)))?;
+        let mut tokens: Vec<llama_token> = Vec::with_capacity(text.len() + usize::from(add_bos));
+        loop {
+            let capacity = c_int::try_from(tokens.capacity()).map_err(|e| LlmError::Tokenize(Box::new(e)))?;
+            // SAFETY: the text pointer and length describe `text`, and llama.cpp
+            // writes at most `capacity` tokens into the buffer
+            let n_tokens = unsafe {
+                llama_cpp_sys_2::llama_tokenize(
+                    self.raw_model(),
+                    text.as_ptr().cast(),
+                    text_len,
+                    tokens.as_mut_ptr(),
+                    capacity,
+                    add_bos,
+                    false,
+                )
+            };
+            if n_tokens >= 0 {
+                // SAFETY: llama.cpp initialised the first `n_tokens` entries
+                unsafe { tokens.set_len(n_tokens as usize) };
+                return Ok(tokens.into_iter().map(LlamaToken::new).collect());
+            }
+            // A negative count is the buffer size that is needed
+            tokens.reserve_exact(n_tokens.unsigned_abs() as usize);
+        }
+    }
+
+    pub(crate) fn to_llama_tokens(&self, tokens: &[u32]) -> Result<Vec<LlamaToken>> {
+        let n_vocab = self.model.n_vocab() as u32;
+        tokens
+            .iter()
+            .map(|&token| {
+                if token >= n_vocab {
+                    return Err(LlmError::InvalidRequest(format!(
+                        "token id {} is out of range for a vocabulary of {} tokens",
+                        token, n_vocab
+                    )));
+                }
+                Ok(LlamaToken::new(token as i32))
+            })
+            .collect()
+    }
+
+    /// Raw llama.cpp handle of the model, for calls llama-cpp-2 does not wrap
+    pub(crate) fn raw_model(&self) -> *mut llama_cpp_sys_2::llama_model {
+        // SAFETY: `LlamaModel` is `#[repr(transparent)]` over its `NonNull<llama_model>`
+        let model: NonNull<llama_cpp_sys_2::llama_model> = unsafe { std::mem::transmute_copy(&self.model) };
+        model.as_ptr()
+    }
+}